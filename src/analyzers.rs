@@ -1,6 +1,18 @@
+use crate::context::{ContextualExpression, ContextualValue};
 use crate::errors;
+use crate::parameters;
+use crate::report;
+use crate::source::{Marker, VerifiableSource};
+use crate::v5;
+use crate::will::Will;
+use evalexpr::Value;
 use paho_mqtt as mqtt;
-use std::cmp::Ordering;
+use regex::Regex;
+use std::cmp::{self, Ordering};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, Instant, SystemTime};
 
 #[derive(Debug, PartialEq)]
 pub enum State {
@@ -10,28 +22,201 @@ pub enum State {
 
 pub trait Analyzer {
     fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError>;
+    /// Short description used to tell analyzers apart when reporting.
+    fn describe(&self) -> String;
+    /// Measurements worth reporting regardless of the outcome.
+    fn summary(&self) -> Option<String> {
+        None
+    }
+    /// Called when no more messages will arrive before the analyzer is done.
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        Err(errors::MqttVerifyError::VerificationFailure {
+            reason: format!("{} did not complete", self.describe()),
+        })
+    }
+}
+
+fn sink_failure(
+    index: usize,
+    sink: &dyn Analyzer,
+    err: errors::MqttVerifyError,
+) -> errors::MqttVerifyError {
+    errors::MqttVerifyError::SinkFailure {
+        sink: format!("{} ({})", index + 1, sink.describe()),
+        source: Box::new(err),
+    }
+}
+
+enum Outcome {
+    Pending,
+    Passed(Duration),
+    Failed(Duration, String),
+}
+
+/// Feeds every message to all sinks and is done once each of them has either
+/// passed or failed. The first failing sink is reported as the error.
+pub struct FanOut {
+    started: Instant,
+    sinks: Vec<(Box<dyn Analyzer>, Outcome)>,
+    failures: Vec<errors::MqttVerifyError>,
+}
+
+impl FanOut {
+    pub fn new(sinks: Vec<Box<dyn Analyzer>>) -> Self {
+        Self {
+            started: Instant::now(),
+            sinks: sinks
+                .into_iter()
+                .map(|sink| (sink, Outcome::Pending))
+                .collect(),
+            failures: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, index: usize, result: Result<State, errors::MqttVerifyError>) {
+        let elapsed = self.started.elapsed();
+        let (sink, outcome) = &mut self.sinks[index];
+        match result {
+            Ok(State::Continue) => (),
+            Ok(State::Done) => *outcome = Outcome::Passed(elapsed),
+            Err(err) => {
+                *outcome = Outcome::Failed(elapsed, err.to_string());
+                self.failures.push(sink_failure(index, sink.as_ref(), err));
+            }
+        }
+    }
+
+    fn resolve(&mut self) -> Result<State, errors::MqttVerifyError> {
+        if self
+            .sinks
+            .iter()
+            .any(|(_, outcome)| matches!(outcome, Outcome::Pending))
+        {
+            Ok(State::Continue)
+        } else if self.failures.is_empty() {
+            Ok(State::Done)
+        } else {
+            Err(self.failures.remove(0))
+        }
+    }
+
+    /// Finish sinks that are still pending because `cause` cut the run short.
+    pub fn expire(&mut self, cause: &str) {
+        for index in 0..self.sinks.len() {
+            if let (sink, Outcome::Pending) = &mut self.sinks[index] {
+                let result = sink.finish().map(|_| State::Done).map_err(|err| {
+                    let reason = match err {
+                        errors::MqttVerifyError::VerificationFailure { reason } => reason,
+                        err => err.to_string(),
+                    };
+                    errors::MqttVerifyError::VerificationFailure {
+                        reason: format!("{}: {}", cause, reason),
+                    }
+                });
+                self.record(index, result);
+            }
+        }
+    }
+
+    pub fn reports(&self) -> Vec<report::SinkReport> {
+        self.sinks
+            .iter()
+            .map(|(sink, outcome)| {
+                let (verdict, reason, elapsed) = match outcome {
+                    Outcome::Pending => (
+                        report::Verdict::Fail,
+                        Some("did not complete".to_owned()),
+                        self.started.elapsed(),
+                    ),
+                    Outcome::Passed(elapsed) => (report::Verdict::Pass, None, *elapsed),
+                    Outcome::Failed(elapsed, reason) => {
+                        (report::Verdict::Fail, Some(reason.clone()), *elapsed)
+                    }
+                };
+                report::SinkReport {
+                    name: sink.describe(),
+                    verdict,
+                    reason,
+                    summary: sink.summary(),
+                    elapsed,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Analyzer for FanOut {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        for index in 0..self.sinks.len() {
+            if let (sink, Outcome::Pending) = &mut self.sinks[index] {
+                let result = sink.analyze(message.clone());
+                self.record(index, result);
+            }
+        }
+        self.resolve()
+    }
+
+    fn describe(&self) -> String {
+        format!("all of {} sinks", self.sinks.len())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let summaries: Vec<String> = self
+            .sinks
+            .iter()
+            .filter_map(|(sink, _)| sink.summary())
+            .collect();
+        if summaries.is_empty() {
+            None
+        } else {
+            Some(summaries.join("\n"))
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        for index in 0..self.sinks.len() {
+            if let (sink, Outcome::Pending) = &mut self.sinks[index] {
+                let result = sink.finish().map(|_| State::Done);
+                self.record(index, result);
+            }
+        }
+        self.resolve().map(|_| ())
+    }
 }
 
 pub struct SessionIdFilter {
     id: String,
+    prefix: String,
     child: Box<dyn Analyzer>,
 }
 
 impl SessionIdFilter {
-    pub fn new(mut id: String, child: Box<dyn Analyzer>) -> Self {
-        id.push(':');
-        Self { id, child }
+    pub fn new(id: String, child: Box<dyn Analyzer>) -> Self {
+        let prefix = format!("{}:", id);
+        Self { id, prefix, child }
     }
 }
 
 impl Analyzer for SessionIdFilter {
     fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
-        if message.payload_str().starts_with(&self.id) {
+        if message.payload_str().starts_with(&self.prefix) {
             self.child.analyze(message)
         } else {
             Ok(State::Continue)
         }
     }
+
+    fn describe(&self) -> String {
+        format!("session {} {}", self.id, self.child.describe())
+    }
+
+    fn summary(&self) -> Option<String> {
+        self.child.summary()
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.child.finish()
+    }
 }
 
 pub struct CountingAnalyzer {
@@ -59,19 +244,1131 @@ impl Analyzer for CountingAnalyzer {
             Ordering::Less => Ok(State::Continue),
         }
     }
+
+    fn describe(&self) -> String {
+        format!("counting {} messages", self.expected_total)
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        Err(errors::MqttVerifyError::VerificationFailure {
+            reason: format!("received {}/{}", self.count, self.expected_total),
+        })
+    }
+}
+
+fn format_seqs(seqs: impl Iterator<Item = usize>) -> String {
+    seqs.map(|seq| seq.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// What MQTT promises about delivery at a given QoS.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Delivery {
+    AtMostOnce,
+    AtLeastOnce,
+    ExactlyOnce,
+}
+
+impl Delivery {
+    pub fn for_qos(qos: i32) -> Self {
+        match qos {
+            0 => Delivery::AtMostOnce,
+            1 => Delivery::AtLeastOnce,
+            _ => Delivery::ExactlyOnce,
+        }
+    }
+
+    fn tolerates_loss(self) -> bool {
+        self == Delivery::AtMostOnce
+    }
+
+    fn tolerates_repeats(self) -> bool {
+        self == Delivery::AtLeastOnce
+    }
+}
+
+#[derive(Default)]
+struct SessionSequence {
+    total: usize,
+    seen: BTreeSet<usize>,
+    repeated: BTreeSet<usize>,
+    reordered: usize,
+    highest: usize,
+}
+
+impl SessionSequence {
+    fn is_complete(&self) -> bool {
+        self.seen.len() == self.total
+    }
+
+    /// Describe the problems that the delivery guarantee either rules out or,
+    /// when `tolerated` is set, permits. Reordering alone is never ruled out,
+    /// as MQTT only orders messages per topic, but is always reported.
+    fn problems(&self, id: &str, delivery: Delivery, tolerated: bool) -> Option<String> {
+        let mut problems = Vec::new();
+        if !self.is_complete() && delivery.tolerates_loss() == tolerated {
+            let missing = (1..=self.total).filter(|seq| !self.seen.contains(seq));
+            problems.push(format!(
+                "received {}/{}, missing {}",
+                self.seen.len(),
+                self.total,
+                format_seqs(missing)
+            ));
+        }
+        if !self.repeated.is_empty() && delivery.tolerates_repeats() == tolerated {
+            problems.push(format!(
+                "repeated {}",
+                format_seqs(self.repeated.iter().cloned())
+            ));
+        }
+        if self.reordered > 0 && (tolerated || !problems.is_empty()) {
+            problems.push(format!("{} out of order", self.reordered));
+        }
+        if problems.is_empty() {
+            None
+        } else {
+            Some(format!("session {} {}", id, problems.join(", ")))
+        }
+    }
+}
+
+/// Tracks sequence numbers of `VerifiableSource` payloads per session id,
+/// reporting lost and repeated messages that the delivery guarantee rules out.
+pub struct SequenceAnalyzer {
+    expected_sessions: usize,
+    delivery: Delivery,
+    sessions: HashMap<String, SessionSequence>,
+}
+
+impl SequenceAnalyzer {
+    pub fn new(expected_sessions: usize, delivery: Delivery) -> Self {
+        Self {
+            expected_sessions,
+            delivery,
+            sessions: HashMap::new(),
+        }
+    }
+
+    fn problems(&self, tolerated: bool) -> Vec<String> {
+        let mut ids: Vec<&String> = self.sessions.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .filter_map(|id| self.sessions[id].problems(id, self.delivery, tolerated))
+            .collect()
+    }
+
+    fn verdict(&self) -> Result<(), errors::MqttVerifyError> {
+        let mut problems = self.problems(false);
+        if self.sessions.len() < self.expected_sessions {
+            problems.push(format!(
+                "saw {}/{} sessions",
+                self.sessions.len(),
+                self.expected_sessions
+            ));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: problems.join("; "),
+            })
+        }
+    }
+}
+
+impl Analyzer for SequenceAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        let payload = message.payload_str();
+        let marker = Marker::parse(&payload).ok_or_else(|| {
+            errors::MqttVerifyError::VerificationFailure {
+                reason: format!("Expected a sequence marker in {}", payload),
+            }
+        })?;
+        let session = self.sessions.entry(marker.id).or_default();
+        let changed_total = !session.seen.is_empty() && session.total != marker.total;
+        if marker.seq == 0 || marker.seq > marker.total || changed_total {
+            return Err(errors::MqttVerifyError::VerificationFailure {
+                reason: format!("Malformed sequence marker in {}", payload),
+            });
+        }
+        session.total = marker.total;
+        if !session.seen.insert(marker.seq) {
+            session.repeated.insert(marker.seq);
+        } else if marker.seq < session.highest {
+            session.reordered += 1;
+        }
+        session.highest = cmp::max(session.highest, marker.seq);
+        if self.sessions.len() >= self.expected_sessions
+            && self.sessions.values().all(|session| session.is_complete())
+        {
+            self.verdict().map(|_| State::Done)
+        } else {
+            Ok(State::Continue)
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!(
+            "sequence of {} sessions {:?}",
+            self.expected_sessions, self.delivery
+        )
+    }
+
+    fn summary(&self) -> Option<String> {
+        let tolerated = self.problems(true);
+        if tolerated.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "tolerated at {:?}: {}",
+                self.delivery,
+                tolerated.join("; ")
+            ))
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.verdict()
+    }
+}
+
+fn format_latency(latency: Duration) -> String {
+    format!("{:.3}ms", latency.as_secs_f64() * 1000.0)
+}
+
+pub struct LatencyStatistics {
+    pub count: usize,
+    pub min: Duration,
+    pub mean: Duration,
+    pub p50: Duration,
+    pub p95: Duration,
+    pub p99: Duration,
+    pub max: Duration,
+}
+
+impl LatencyStatistics {
+    pub fn from_latencies(latencies: &[Duration]) -> Option<Self> {
+        if latencies.is_empty() {
+            return None;
+        }
+        let mut sorted = latencies.to_vec();
+        sorted.sort();
+        let percentile = |p: f64| {
+            let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+            sorted[cmp::max(rank, 1) - 1]
+        };
+        Some(Self {
+            count: sorted.len(),
+            min: sorted[0],
+            mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p50: percentile(50.0),
+            p95: percentile(95.0),
+            p99: percentile(99.0),
+            max: sorted[sorted.len() - 1],
+        })
+    }
+}
+
+impl fmt::Display for LatencyStatistics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "n={} min={} mean={} p50={} p95={} p99={} max={}",
+            self.count,
+            format_latency(self.min),
+            format_latency(self.mean),
+            format_latency(self.p50),
+            format_latency(self.p95),
+            format_latency(self.p99),
+            format_latency(self.max)
+        )
+    }
+}
+
+/// Upper bounds on overall latency; exceeding any of them fails verification.
+#[derive(Clone, Debug, Default)]
+pub struct LatencyThresholds {
+    pub p50: Option<Duration>,
+    pub p95: Option<Duration>,
+    pub p99: Option<Duration>,
+    pub max: Option<Duration>,
+}
+
+impl LatencyThresholds {
+    pub fn is_set(&self) -> bool {
+        self.p50.is_some() || self.p95.is_some() || self.p99.is_some() || self.max.is_some()
+    }
+
+    fn check(&self, statistics: &LatencyStatistics) -> Result<(), errors::MqttVerifyError> {
+        let limits = vec![
+            ("p50", self.p50, statistics.p50),
+            ("p95", self.p95, statistics.p95),
+            ("p99", self.p99, statistics.p99),
+            ("max", self.max, statistics.max),
+        ];
+        let exceeded: Vec<String> = limits
+            .into_iter()
+            .filter_map(|(name, limit, actual)| match limit {
+                Some(limit) if actual > limit => Some(format!(
+                    "{} latency {} exceeds {}",
+                    name,
+                    format_latency(actual),
+                    format_latency(limit)
+                )),
+                _ => None,
+            })
+            .collect();
+        if exceeded.is_empty() {
+            Ok(())
+        } else {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: exceeded.join(", "),
+            })
+        }
+    }
+}
+
+/// Measures time from the send timestamp in each payload marker until the
+/// message arrives, per session and overall. Repeated deliveries of a message
+/// are not measured again.
+pub struct LatencyAnalyzer {
+    expected_total: usize,
+    thresholds: LatencyThresholds,
+    latencies: HashMap<String, Vec<Duration>>,
+    seen: BTreeSet<(String, usize)>,
+}
+
+impl LatencyAnalyzer {
+    pub fn new(expected_total: usize, thresholds: LatencyThresholds) -> Self {
+        Self {
+            expected_total,
+            thresholds,
+            latencies: HashMap::new(),
+            seen: BTreeSet::new(),
+        }
+    }
+
+    pub fn statistics(&self) -> Option<LatencyStatistics> {
+        let all: Vec<Duration> = self.latencies.values().flatten().cloned().collect();
+        LatencyStatistics::from_latencies(&all)
+    }
+
+    fn verdict(&self) -> Result<(), errors::MqttVerifyError> {
+        match self.statistics() {
+            Some(statistics) => self.thresholds.check(&statistics),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Analyzer for LatencyAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        let payload = message.payload_str();
+        let (id, seq, sent) = match Marker::parse(&payload) {
+            Some(Marker {
+                id,
+                seq,
+                sent: Some(sent),
+                ..
+            }) => (id, seq, sent),
+            _ => {
+                return Err(errors::MqttVerifyError::VerificationFailure {
+                    reason: format!("Expected a send timestamp in {}", payload),
+                })
+            }
+        };
+        // Clocks may differ slightly between publisher and subscriber hosts
+        let latency = SystemTime::now().duration_since(sent).unwrap_or_default();
+        if self.seen.insert((id.clone(), seq)) {
+            self.latencies.entry(id).or_default().push(latency);
+        }
+        if self.seen.len() >= self.expected_total {
+            self.verdict().map(|_| State::Done)
+        } else {
+            Ok(State::Continue)
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("latency of {} messages", self.expected_total)
+    }
+
+    fn summary(&self) -> Option<String> {
+        let mut ids: Vec<&String> = self.latencies.keys().collect();
+        ids.sort();
+        let mut lines: Vec<String> = ids
+            .into_iter()
+            .filter_map(|id| {
+                LatencyStatistics::from_latencies(&self.latencies[id])
+                    .map(|statistics| format!("latency session {}: {}", id, statistics))
+            })
+            .collect();
+        lines.push(format!("latency overall: {}", self.statistics()?));
+        Some(lines.join("\n"))
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.verdict()
+    }
+}
+
+enum Step {
+    Key(String),
+    Index(usize),
+}
+
+/// A path into a JSON document such as `$.readings[0].value`.
+pub struct JsonPath {
+    source: String,
+    steps: Vec<Step>,
+}
+
+impl JsonPath {
+    pub fn parse(path: &str) -> Result<Self, errors::MqttVerifyError> {
+        let malformed = || errors::MqttVerifyError::MalformedValue {
+            value: path.to_owned(),
+        };
+        if !path.starts_with('$') {
+            return Err(malformed());
+        }
+        let mut steps = Vec::new();
+        let mut rest = &path[1..];
+        while !rest.is_empty() {
+            if rest.starts_with('.') {
+                let end = rest[1..]
+                    .find(&['.', '['][..])
+                    .map_or(rest.len(), |end| end + 1);
+                if end == 1 {
+                    return Err(malformed());
+                }
+                steps.push(Step::Key(rest[1..end].to_owned()));
+                rest = &rest[end..];
+            } else if rest.starts_with('[') {
+                let end = rest.find(']').ok_or_else(malformed)?;
+                let index = rest[1..end].trim().parse().map_err(|_| malformed())?;
+                steps.push(Step::Index(index));
+                rest = &rest[end + 1..];
+            } else {
+                return Err(malformed());
+            }
+        }
+        Ok(Self {
+            source: path.to_owned(),
+            steps,
+        })
+    }
+
+    fn select<'a>(&self, json: &'a serde_json::Value) -> Option<&'a serde_json::Value> {
+        self.steps.iter().try_fold(json, |json, step| match step {
+            Step::Key(key) => json.get(key),
+            Step::Index(index) => json.get(index),
+        })
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+/// What a `PayloadMatchAnalyzer` requires of each message. Regexes and
+/// templates apply to the payload body following any verification marker.
+pub enum PayloadMatch {
+    Regex(Box<Regex>),
+    /// Body equal to the template expanded with the message variables
+    Template(ContextualValue),
+    /// Predicate over the JSON value at the path in the body, bound as `value`
+    JsonPath(JsonPath, ContextualExpression),
+    /// Boolean expression over the message variables
+    Expression(ContextualExpression),
+}
+
+impl fmt::Display for PayloadMatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PayloadMatch::Regex(regex) => write!(f, "matching /{}/", regex),
+            PayloadMatch::Template(template) => write!(f, "equal to {}", template),
+            PayloadMatch::JsonPath(path, predicate) => write!(f, "with {} {}", path, predicate),
+            PayloadMatch::Expression(expression) => write!(f, "satisfying {}", expression),
+        }
+    }
+}
+
+/// Variables describing a received message: `topic`, `payload`, `body` (the
+/// payload less its marker), `qos` and `retained`, plus those of the marker
+/// if there is one and of MQTT v5 properties.
+pub fn message_variables(message: &mqtt::Message) -> Vec<(String, Value)> {
+    let payload = message.payload_str();
+    let mut variables = match Marker::parse(&payload) {
+        Some(marker) => marker.variables(),
+        None => Vec::new(),
+    };
+    variables.push((
+        "topic".to_owned(),
+        Value::String(message.topic().to_owned()),
+    ));
+    variables.push((
+        "body".to_owned(),
+        Value::String(Marker::strip(&payload).to_owned()),
+    ));
+    variables.push(("payload".to_owned(), Value::String(payload.into_owned())));
+    variables.push(("qos".to_owned(), Value::Int(message.qos() as i64)));
+    variables.push(("retained".to_owned(), Value::Boolean(message.retained())));
+    variables.extend(v5::variables(message));
+    variables
+}
+
+/// Variables of a sample message for validating templates up front, plus
+/// samples of any of `names` that only some messages have.
+fn sample_variables(names: Vec<String>) -> Vec<(String, Value)> {
+    let marker = Marker::new("id".to_owned(), 1, 1);
+    let mut variables = message_variables(&mqtt::Message::new("topic", marker.to_string(), 0));
+    variables.extend(
+        names
+            .into_iter()
+            .filter_map(|name| v5::sample_variable(&name).map(|value| (name, value))),
+    );
+    variables
+}
+
+impl PayloadMatch {
+    /// Check templates and expressions against the variables of a sample
+    /// message.
+    pub fn validate(&self) -> Result<(), errors::MqttVerifyError> {
+        match self {
+            PayloadMatch::Regex(_) => Ok(()),
+            PayloadMatch::Template(template) => {
+                template.validate(sample_variables(template.variables()))
+            }
+            PayloadMatch::JsonPath(_, predicate) => {
+                let mut variables = sample_variables(predicate.variables());
+                variables.push(("value".to_owned(), Value::Empty));
+                predicate.validate(variables)
+            }
+            PayloadMatch::Expression(expression) => {
+                expression.validate(sample_variables(expression.variables()))
+            }
+        }
+    }
+
+    /// Describe why `message` does not match, if it doesn't, as the payload
+    /// and topic followed by the unmet requirement.
+    fn mismatch(&self, message: &mqtt::Message) -> Result<Option<String>, errors::MqttVerifyError> {
+        let payload = message.payload_str();
+        Ok(self
+            .unmet(message)?
+            .map(|requirement| format!("{} on {} not {}", payload, message.topic(), requirement)))
+    }
+
+    /// The requirement `message` does not meet, if any.
+    fn unmet(&self, message: &mqtt::Message) -> Result<Option<String>, errors::MqttVerifyError> {
+        let payload = message.payload_str();
+        let body = Marker::strip(&payload);
+        let matched = match self {
+            PayloadMatch::Regex(regex) => regex.is_match(body),
+            PayloadMatch::Template(template) => {
+                let expected = template.value_with(message_variables(message))?;
+                if body != expected {
+                    return Ok(Some(format!("equal to {}", expected)));
+                }
+                true
+            }
+            PayloadMatch::JsonPath(path, predicate) => {
+                let json: serde_json::Value = match serde_json::from_str(body) {
+                    Ok(json) => json,
+                    Err(err) => return Ok(Some(format!("JSON: {}", err))),
+                };
+                let selected = match path.select(&json) {
+                    Some(selected) => selected,
+                    None => return Ok(Some(format!("with {}", path))),
+                };
+                let value = parameters::from_json(selected)
+                    .unwrap_or_else(|_| Value::String(selected.to_string()));
+                let mut variables = message_variables(message);
+                variables.push(("value".to_owned(), value));
+                predicate.boolean_with(variables)?
+            }
+            PayloadMatch::Expression(expression) => {
+                expression.boolean_with(message_variables(message))?
+            }
+        };
+        Ok(if matched {
+            None
+        } else {
+            Some(self.to_string())
+        })
+    }
+}
+
+/// Checks every message against a `PayloadMatch` and passes it on to a
+/// child analyzer, which decides when enough messages have arrived.
+pub struct PayloadMatchAnalyzer {
+    matcher: PayloadMatch,
+    child: Box<dyn Analyzer>,
+    checked: usize,
+    mismatched: usize,
+    first_mismatch: Option<String>,
+}
+
+impl PayloadMatchAnalyzer {
+    pub fn new(matcher: PayloadMatch, child: Box<dyn Analyzer>) -> Self {
+        Self {
+            matcher,
+            child,
+            checked: 0,
+            mismatched: 0,
+            first_mismatch: None,
+        }
+    }
+
+    fn verdict(&self) -> Result<(), errors::MqttVerifyError> {
+        match self.first_mismatch {
+            Some(ref first) => Err(errors::MqttVerifyError::VerificationFailure {
+                reason: format!(
+                    "{}/{} payloads did not match, first {}",
+                    self.mismatched, self.checked, first
+                ),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Analyzer for PayloadMatchAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        self.checked += 1;
+        if let Some(mismatch) = self.matcher.mismatch(&message)? {
+            self.mismatched += 1;
+            self.first_mismatch.get_or_insert(mismatch);
+        }
+        match self.child.analyze(message)? {
+            State::Done => self.verdict().map(|_| State::Done),
+            State::Continue => Ok(State::Continue),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("payload {} {}", self.matcher, self.child.describe())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let matched = format!(
+            "matched {}/{} payloads",
+            self.checked - self.mismatched,
+            self.checked
+        );
+        Some(match self.child.summary() {
+            Some(summary) => format!("{}\n{}", matched, summary),
+            None => matched,
+        })
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.child.finish()?;
+        self.verdict()
+    }
+}
+
+/// Topic templates of the sources publishing each session, by session id.
+pub type PublishedTopics = HashMap<String, Rc<ContextualValue>>;
+
+#[derive(Default)]
+struct SessionTopics {
+    checked: usize,
+    mismatched: usize,
+    first_mismatch: Option<String>,
+}
+
+/// Checks that every message arrives on the topic `expected` expands to with
+/// the message variables, plus `published` for the topic its source published
+/// it to, e.g. `{{replace(published, "a/", "b/")}}` across a bridge. Messages
+/// are passed on to a child analyzer, which decides when enough have arrived.
+pub struct TopicAnalyzer {
+    expected: ContextualValue,
+    published: PublishedTopics,
+    sessions: HashMap<String, SessionTopics>,
+    child: Box<dyn Analyzer>,
+}
+
+impl TopicAnalyzer {
+    pub fn new(
+        expected: ContextualValue,
+        published: PublishedTopics,
+        child: Box<dyn Analyzer>,
+    ) -> Self {
+        Self {
+            expected,
+            published,
+            sessions: HashMap::new(),
+            child,
+        }
+    }
+
+    /// Check the expected topic template against the variables of a sample
+    /// message.
+    pub fn validate(&self) -> Result<(), errors::MqttVerifyError> {
+        let mut variables = sample_variables(self.expected.variables());
+        variables.push(("published".to_owned(), Value::String(String::new())));
+        self.expected.validate(variables)
+    }
+
+    fn verdict(&self) -> Result<(), errors::MqttVerifyError> {
+        let mut ids: Vec<&String> = self.sessions.keys().collect();
+        ids.sort();
+        let problems: Vec<String> = ids
+            .into_iter()
+            .filter_map(|id| {
+                let session = &self.sessions[id];
+                session.first_mismatch.as_ref().map(|first| {
+                    format!(
+                        "session {} {}/{} on unexpected topics, first {}",
+                        id, session.mismatched, session.checked, first
+                    )
+                })
+            })
+            .collect();
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: problems.join("; "),
+            })
+        }
+    }
+}
+
+impl Analyzer for TopicAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        let payload = message.payload_str();
+        let marker = Marker::parse(&payload).ok_or_else(|| {
+            errors::MqttVerifyError::VerificationFailure {
+                reason: format!("Expected a sequence marker in {}", payload),
+            }
+        })?;
+        let mut variables = message_variables(&message);
+        if let Some(topic) = self.published.get(&marker.id) {
+            let published = topic.value_with(marker.variables())?;
+            variables.push(("published".to_owned(), Value::String(published)));
+        }
+        let expected = self.expected.value_with(variables)?;
+        let session = self.sessions.entry(marker.id.clone()).or_default();
+        session.checked += 1;
+        if expected != message.topic() {
+            session.mismatched += 1;
+            session.first_mismatch.get_or_insert_with(|| {
+                format!("{} on {} instead of {}", marker, message.topic(), expected)
+            });
+        }
+        match self.child.analyze(message)? {
+            State::Done => self.verdict().map(|_| State::Done),
+            State::Continue => Ok(State::Continue),
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("topic {} {}", self.expected, self.child.describe())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let checked: usize = self.sessions.values().map(|session| session.checked).sum();
+        let mismatched: usize = self
+            .sessions
+            .values()
+            .map(|session| session.mismatched)
+            .sum();
+        let matched = format!("matched {}/{} topics", checked - mismatched, checked);
+        Some(match self.child.summary() {
+            Some(summary) => format!("{}\n{}", matched, summary),
+            None => matched,
+        })
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.child.finish()?;
+        self.verdict()
+    }
+}
+
+fn format_markers(markers: &[Marker]) -> String {
+    markers
+        .iter()
+        .map(|marker| marker.to_string())
+        .collect::<Vec<_>>()
+        .join(" or ")
+}
+
+/// Checks that a subscriber connecting after publishing has finished gets
+/// exactly the last retained message published to each topic, flagged as
+/// retained.
+pub struct RetainedAnalyzer {
+    /// Acceptable markers by topic; several when sources share a topic
+    expected: BTreeMap<String, Vec<Marker>>,
+    received: BTreeMap<String, usize>,
+    problems: Vec<String>,
+}
+
+impl RetainedAnalyzer {
+    pub fn new(expected: BTreeMap<String, Vec<Marker>>) -> Self {
+        Self {
+            expected,
+            received: BTreeMap::new(),
+            problems: Vec::new(),
+        }
+    }
+
+    /// Expect what the retained sources among `sources` leave on the broker.
+    pub fn for_sources<'a>(
+        sources: impl Iterator<Item = &'a VerifiableSource>,
+    ) -> Result<Self, errors::MqttVerifyError> {
+        let mut expected: BTreeMap<String, Vec<Marker>> = BTreeMap::new();
+        for source in sources {
+            for (topic, marker) in source.retained_values()? {
+                expected.entry(topic).or_default().push(marker);
+            }
+        }
+        Ok(Self::new(expected))
+    }
+
+    fn verdict(&self) -> Result<(), errors::MqttVerifyError> {
+        let mut problems = self.problems.clone();
+        let missing: Vec<&str> = self
+            .expected
+            .keys()
+            .filter(|topic| !self.received.contains_key(*topic))
+            .map(String::as_str)
+            .collect();
+        if !missing.is_empty() {
+            problems.push(format!("nothing retained on {}", missing.join(", ")));
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: problems.join("; "),
+            })
+        }
+    }
+}
+
+impl Analyzer for RetainedAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        let topic = message.topic();
+        let payload = message.payload_str();
+        let count = self.received.entry(topic.to_owned()).or_default();
+        *count += 1;
+        match self.expected.get(topic) {
+            None => self
+                .problems
+                .push(format!("unexpected {} on {}", payload, topic)),
+            Some(candidates) => {
+                if *count > 1 {
+                    self.problems
+                        .push(format!("repeated {} on {}", payload, topic));
+                }
+                if !message.retained() {
+                    self.problems
+                        .push(format!("{} on {} not flagged retained", payload, topic));
+                }
+                let latest = match Marker::parse(&payload) {
+                    Some(marker) => candidates
+                        .iter()
+                        .any(|candidate| candidate.id == marker.id && candidate.seq == marker.seq),
+                    None => false,
+                };
+                if !latest {
+                    self.problems.push(format!(
+                        "{} on {} instead of {}",
+                        payload,
+                        topic,
+                        format_markers(candidates)
+                    ));
+                }
+            }
+        }
+        if self
+            .expected
+            .keys()
+            .all(|topic| self.received.contains_key(topic))
+        {
+            self.verdict().map(|_| State::Done)
+        } else {
+            Ok(State::Continue)
+        }
+    }
+
+    fn describe(&self) -> String {
+        format!("retained on {} topics", self.expected.len())
+    }
+
+    fn summary(&self) -> Option<String> {
+        let received = self
+            .expected
+            .keys()
+            .filter(|topic| self.received.contains_key(*topic))
+            .count();
+        Some(format!(
+            "received retained on {}/{} topics",
+            received,
+            self.expected.len()
+        ))
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        self.verdict()
+    }
+}
+
+/// Checks whether the will of a publisher is published: it should be when the
+/// publisher drops its connection and must not be when it disconnects. Other
+/// messages are ignored, so an absent will passes only when the subscriber
+/// gives up on its idle timeout or the scenario deadline.
+pub struct WillAnalyzer {
+    will: Will,
+    expected: bool,
+}
+
+impl WillAnalyzer {
+    pub fn new(will: Will, expected: bool) -> Self {
+        Self { will, expected }
+    }
+}
+
+impl Analyzer for WillAnalyzer {
+    fn analyze(&mut self, message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+        if message.topic() != self.will.topic || message.payload_str() != self.will.payload {
+            Ok(State::Continue)
+        } else if self.expected {
+            Ok(State::Done)
+        } else {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: format!(
+                    "will on {} published despite a graceful disconnect",
+                    self.will.topic
+                ),
+            })
+        }
+    }
+
+    fn describe(&self) -> String {
+        if self.expected {
+            format!("will on {}", self.will.topic)
+        } else {
+            format!("no will on {}", self.will.topic)
+        }
+    }
+
+    fn finish(&mut self) -> Result<(), errors::MqttVerifyError> {
+        if self.expected {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: format!("no will on {}", self.will.topic),
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Verifies the members of a shared subscription group together: the merged
+/// messages of all members go to the group's sinks, typically a sequence
+/// check expecting every message exactly once, and each member should get at
+/// least `min_share` of an even share of them.
+pub struct GroupAnalyzer {
+    sinks: FanOut,
+    members: Vec<(String, usize)>,
+    min_share: f64,
+}
+
+impl GroupAnalyzer {
+    pub fn new(members: Vec<String>, sinks: Vec<Box<dyn Analyzer>>, min_share: f64) -> Self {
+        Self {
+            sinks: FanOut::new(sinks),
+            members: members.into_iter().map(|name| (name, 0)).collect(),
+            min_share,
+        }
+    }
+
+    /// Analyze a message delivered to the member at `member`.
+    pub fn analyze(
+        &mut self,
+        member: usize,
+        message: mqtt::Message,
+    ) -> Result<State, errors::MqttVerifyError> {
+        self.members[member].1 += 1;
+        self.sinks.analyze(message)
+    }
+
+    /// How many messages the member at `member` received.
+    pub fn received(&self, member: usize) -> usize {
+        self.members[member].1
+    }
+
+    pub fn expire(&mut self, cause: &str) {
+        self.sinks.expire(cause);
+    }
+
+    /// Members falling short of their share.
+    fn shortfall(&self) -> Option<String> {
+        let total: usize = self.members.iter().map(|(_, received)| received).sum();
+        let least = self.min_share * total as f64 / self.members.len() as f64;
+        let short: Vec<String> = self
+            .members
+            .iter()
+            .filter(|(_, received)| (*received as f64) < least)
+            .map(|(name, received)| format!("{} ({})", name, received))
+            .collect();
+        if short.is_empty() {
+            None
+        } else {
+            Some(format!(
+                "uneven share of {} messages, less than {:.1} to {}",
+                total,
+                least,
+                short.join(", ")
+            ))
+        }
+    }
+
+    /// The share of each member, followed by the reports of the group's sinks.
+    pub fn reports(&self) -> Vec<report::SinkReport> {
+        let total: usize = self.members.iter().map(|(_, received)| received).sum();
+        let summary = self
+            .members
+            .iter()
+            .map(|(name, received)| {
+                let percent = match total {
+                    0 => 0.0,
+                    total => 100.0 * *received as f64 / total as f64,
+                };
+                format!("{} received {} ({:.1}%)", name, received, percent)
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+        let reason = self.shortfall();
+        let mut reports = vec![report::SinkReport {
+            name: format!("share among {} members", self.members.len()),
+            verdict: match reason {
+                Some(_) => report::Verdict::Fail,
+                None => report::Verdict::Pass,
+            },
+            reason,
+            summary: Some(summary),
+            elapsed: self.sinks.started.elapsed(),
+        }];
+        reports.extend(self.sinks.reports());
+        reports
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Analyzer, State};
+    use super::{Analyzer, PayloadMatch, State};
+    use crate::context::OverlayContext;
     use crate::errors;
+    use crate::report;
+    use crate::source::Marker;
     use paho_mqtt as mqtt;
+    use std::rc::Rc;
+    use std::time::{Duration, SystemTime};
 
     struct DoneAnalyzer;
     impl super::Analyzer for DoneAnalyzer {
         fn analyze(&mut self, _message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
             Ok(State::Done)
         }
+
+        fn describe(&self) -> String {
+            "done".to_owned()
+        }
+    }
+
+    struct FailingAnalyzer;
+    impl super::Analyzer for FailingAnalyzer {
+        fn analyze(&mut self, _message: mqtt::Message) -> Result<State, errors::MqttVerifyError> {
+            Err(errors::MqttVerifyError::VerificationFailure {
+                reason: "borked".to_owned(),
+            })
+        }
+
+        fn describe(&self) -> String {
+            "failing".to_owned()
+        }
+    }
+
+    #[test]
+    fn fan_out_done_when_all_sinks_done() {
+        let mut fan_out = super::FanOut::new(vec![
+            Box::new(DoneAnalyzer {}),
+            Box::new(super::CountingAnalyzer::new(2)),
+        ]);
+        let message = mqtt::Message::new("ze-topic", "message", 0);
+        assert_eq!(State::Continue, fan_out.analyze(message.clone()).unwrap());
+        assert_eq!(State::Done, fan_out.analyze(message).unwrap());
+    }
+
+    #[test]
+    fn fan_out_finishes_incomplete_sinks() {
+        let mut fan_out = super::FanOut::new(vec![
+            Box::new(DoneAnalyzer {}),
+            Box::new(super::CountingAnalyzer::new(2)),
+        ]);
+        fan_out
+            .analyze(mqtt::Message::new("ze-topic", "message", 0))
+            .unwrap();
+        match fan_out.finish() {
+            Err(errors::MqttVerifyError::SinkFailure { sink, source }) => {
+                assert_eq!("2 (counting 2 messages)", sink);
+                assert_eq!("Verification failed: received 1/2", source.to_string());
+            }
+            _ => panic!("Expected a sink failure"),
+        };
+    }
+
+    #[test]
+    fn fan_out_reports_each_sink() {
+        let mut fan_out = super::FanOut::new(vec![
+            Box::new(FailingAnalyzer {}),
+            Box::new(super::CountingAnalyzer::new(2)),
+        ]);
+        let message = mqtt::Message::new("ze-topic", "message", 0);
+        assert!(fan_out.analyze(message.clone()).is_ok());
+        assert!(fan_out.analyze(message).is_err());
+        let reports = fan_out.reports();
+        assert_eq!(report::Verdict::Fail, reports[0].verdict);
+        assert_eq!(
+            Some("Verification failed: borked".to_owned()),
+            reports[0].reason
+        );
+        assert_eq!(report::Verdict::Pass, reports[1].verdict);
+        assert_eq!("counting 2 messages", reports[1].name);
+    }
+
+    #[test]
+    fn fan_out_expire_describes_outstanding() {
+        let mut fan_out = super::FanOut::new(vec![Box::new(super::SessionIdFilter::new(
+            "2".to_owned(),
+            Box::new(super::CountingAnalyzer::new(10)),
+        ))]);
+        fan_out.expire("deadline exceeded");
+        assert_eq!(
+            Some("Verification failed: deadline exceeded: received 0/10".to_owned()),
+            fan_out.reports()[0].reason
+        );
+    }
+
+    #[test]
+    fn fan_out_names_failing_sink() {
+        let mut fan_out = super::FanOut::new(vec![
+            Box::new(DoneAnalyzer {}),
+            Box::new(FailingAnalyzer {}),
+        ]);
+        match fan_out.analyze(mqtt::Message::new("ze-topic", "message", 0)) {
+            Err(errors::MqttVerifyError::SinkFailure { sink, source: _ }) => {
+                assert_eq!("2 (failing)", sink)
+            }
+            _ => panic!("Expected a sink failure"),
+        };
     }
 
     #[test]
@@ -92,6 +1389,216 @@ mod tests {
         assert_eq!(State::Continue, filter.analyze(ignored_message).unwrap());
     }
 
+    #[test]
+    fn group_analyzer_checks_exactly_once_and_shares() {
+        let sequence = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        let members = vec!["s-1".to_owned(), "s-2".to_owned(), "s-3".to_owned()];
+        let mut analyzer = super::GroupAnalyzer::new(members, vec![Box::new(sequence)], 0.5);
+        let deliveries = [(0, "a:1/4"), (1, "a:2/4"), (0, "a:3/4")];
+        for (member, payload) in &deliveries {
+            let message = mqtt::Message::new("ze-topic", *payload, 1);
+            assert_eq!(State::Continue, analyzer.analyze(*member, message).unwrap());
+        }
+        let last = mqtt::Message::new("ze-topic", "a:4/4", 1);
+        assert_eq!(State::Done, analyzer.analyze(1, last).unwrap());
+        assert_eq!(2, analyzer.received(0));
+        let reports = analyzer.reports();
+        assert_eq!("share among 3 members", reports[0].name);
+        assert_eq!(report::Verdict::Fail, reports[0].verdict);
+        assert_eq!(
+            Some("uneven share of 4 messages, less than 0.7 to s-3 (0)"),
+            reports[0].reason.as_deref()
+        );
+        assert_eq!(
+            Some("s-1 received 2 (50.0%)\ns-2 received 2 (50.0%)\ns-3 received 0 (0.0%)"),
+            reports[0].summary.as_deref()
+        );
+        assert_eq!(report::Verdict::Pass, reports[1].verdict);
+    }
+
+    #[test]
+    fn group_analyzer_fails_repeats_across_members() {
+        let sequence = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        let members = vec!["s-1".to_owned(), "s-2".to_owned()];
+        let mut analyzer = super::GroupAnalyzer::new(members, vec![Box::new(sequence)], 0.5);
+        for (member, payload) in &[(0, "a:1/2"), (1, "a:1/2"), (1, "a:2/2")] {
+            let _ = analyzer.analyze(*member, mqtt::Message::new("ze-topic", *payload, 1));
+        }
+        let reports = analyzer.reports();
+        assert_eq!(report::Verdict::Pass, reports[0].verdict);
+        assert_eq!(report::Verdict::Fail, reports[1].verdict);
+    }
+
+    #[test]
+    fn sequence_analyzer() {
+        let mut analyzer = super::SequenceAnalyzer::new(2, super::Delivery::ExactlyOnce);
+        for payload in &["a:1/2", "b:2/2", "a:2/2", "b:1/2"] {
+            analyzer
+                .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                .unwrap();
+        }
+        assert!(analyzer.finish().is_ok());
+    }
+
+    #[test]
+    fn sequence_analyzer_done() {
+        let mut analyzer = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        let first = mqtt::Message::new("ze-topic", "a:1/2", 0);
+        let second = mqtt::Message::new("ze-topic", "a:2/2", 0);
+        assert_eq!(State::Continue, analyzer.analyze(first).unwrap());
+        assert_eq!(State::Done, analyzer.analyze(second).unwrap());
+    }
+
+    #[test]
+    fn sequence_analyzer_reports_missing_and_repeated() {
+        let mut analyzer = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        for payload in &["a:1/4", "a:3/4", "a:3/4"] {
+            analyzer
+                .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                .unwrap();
+        }
+        match analyzer.finish() {
+            Err(errors::MqttVerifyError::VerificationFailure { reason }) => {
+                assert_eq!("session a received 2/4, missing 2, 4, repeated 3", reason)
+            }
+            _ => panic!("Expected a verification failure"),
+        };
+    }
+
+    #[test]
+    fn sequence_analyzer_delivery_guarantees() {
+        let payloads = ["a:1/3", "a:1/3", "a:3/3"];
+        let outcome = |delivery| {
+            let mut analyzer = super::SequenceAnalyzer::new(1, delivery);
+            for payload in &payloads {
+                analyzer
+                    .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                    .unwrap();
+            }
+            analyzer.finish().map_err(|err| err.to_string())
+        };
+        assert_eq!(
+            Err("Verification failed: session a repeated 1".to_owned()),
+            outcome(super::Delivery::AtMostOnce)
+        );
+        assert_eq!(
+            Err("Verification failed: session a received 2/3, missing 2".to_owned()),
+            outcome(super::Delivery::AtLeastOnce)
+        );
+        assert_eq!(
+            Err("Verification failed: session a received 2/3, missing 2, repeated 1".to_owned()),
+            outcome(super::Delivery::ExactlyOnce)
+        );
+    }
+
+    #[test]
+    fn sequence_analyzer_rejects_unmarked_payload() {
+        let mut analyzer = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        assert!(analyzer
+            .analyze(mqtt::Message::new("ze-topic", "garbage", 0))
+            .is_err());
+    }
+
+    #[test]
+    fn sequence_analyzer_rejects_malformed_markers() {
+        for payloads in &[&["a:0/2"][..], &["a:3/2"], &["a:1/2", "a:2/3"]] {
+            let mut analyzer = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+            let (last, first) = payloads.split_last().unwrap();
+            for payload in first {
+                analyzer
+                    .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                    .unwrap();
+            }
+            match analyzer.analyze(mqtt::Message::new("ze-topic", *last, 0)) {
+                Err(errors::MqttVerifyError::VerificationFailure { reason }) => {
+                    assert_eq!(format!("Malformed sequence marker in {}", last), reason)
+                }
+                _ => panic!("Expected {} to be rejected", last),
+            }
+        }
+    }
+
+    #[test]
+    fn sequence_analyzer_reports_reordering() {
+        let mut analyzer = super::SequenceAnalyzer::new(1, super::Delivery::ExactlyOnce);
+        for payload in &["a:2/3", "a:1/3", "a:3/3"] {
+            analyzer
+                .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                .unwrap();
+        }
+        assert!(analyzer.finish().is_ok());
+        assert_eq!(
+            Some("tolerated at ExactlyOnce: session a 1 out of order".to_owned()),
+            analyzer.summary()
+        );
+    }
+
+    #[test]
+    fn latency_statistics() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        let statistics = super::LatencyStatistics::from_latencies(&latencies).unwrap();
+        assert_eq!(Duration::from_millis(1), statistics.min);
+        assert_eq!(Duration::from_micros(50_500), statistics.mean);
+        assert_eq!(Duration::from_millis(50), statistics.p50);
+        assert_eq!(Duration::from_millis(95), statistics.p95);
+        assert_eq!(Duration::from_millis(99), statistics.p99);
+        assert_eq!(Duration::from_millis(100), statistics.max);
+        assert!(super::LatencyStatistics::from_latencies(&[]).is_none());
+    }
+
+    #[test]
+    fn latency_analyzer_thresholds() {
+        let thresholds = super::LatencyThresholds {
+            p99: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+        let mut analyzer = super::LatencyAnalyzer::new(2, thresholds);
+        let sent = SystemTime::now() - Duration::from_secs(1);
+        let fast = Marker::new("a".to_owned(), 1, 2);
+        let slow = Marker {
+            sent: Some(sent),
+            ..Marker::new("a".to_owned(), 2, 2)
+        };
+        let fast = mqtt::Message::new("ze-topic", fast.to_string(), 0);
+        let slow = mqtt::Message::new("ze-topic", slow.to_string(), 0);
+        assert_eq!(State::Continue, analyzer.analyze(fast).unwrap());
+        match analyzer.analyze(slow) {
+            Err(errors::MqttVerifyError::VerificationFailure { reason }) => {
+                assert!(reason.starts_with("p99 latency"))
+            }
+            _ => panic!("Expected a verification failure"),
+        };
+        assert!(analyzer
+            .summary()
+            .unwrap()
+            .contains("latency session a: n=2"));
+    }
+
+    #[test]
+    fn latency_analyzer_ignores_repeats() {
+        let mut analyzer = super::LatencyAnalyzer::new(2, Default::default());
+        let first = Marker::new("a".to_owned(), 1, 2).to_string();
+        let second = Marker::new("a".to_owned(), 2, 2).to_string();
+        let first = mqtt::Message::new("ze-topic", first, 1);
+        assert_eq!(State::Continue, analyzer.analyze(first.clone()).unwrap());
+        assert_eq!(State::Continue, analyzer.analyze(first).unwrap());
+        assert_eq!(
+            State::Done,
+            analyzer
+                .analyze(mqtt::Message::new("ze-topic", second, 1))
+                .unwrap()
+        );
+        assert_eq!(2, analyzer.statistics().unwrap().count);
+    }
+
+    #[test]
+    fn latency_analyzer_requires_timestamp() {
+        let mut analyzer = super::LatencyAnalyzer::new(1, Default::default());
+        assert!(analyzer
+            .analyze(mqtt::Message::new("ze-topic", "a:1/1", 0))
+            .is_err());
+    }
+
     #[test]
     fn counting_analyzer() {
         let mut analyzer = super::CountingAnalyzer::new(3);
@@ -104,4 +1611,241 @@ mod tests {
             _ => panic!("Expected a verification failure"),
         };
     }
+
+    fn payload_match(matcher: PayloadMatch, payloads: &[&str]) -> Result<State, String> {
+        let count = payloads.len();
+        let mut analyzer = super::PayloadMatchAnalyzer::new(
+            matcher,
+            Box::new(super::CountingAnalyzer::new(count)),
+        );
+        let mut state = Ok(State::Continue);
+        for payload in payloads {
+            state = analyzer.analyze(mqtt::Message::new("ze-topic", *payload, 1));
+        }
+        state.map_err(|err| err.to_string())
+    }
+
+    #[test]
+    fn payload_match_regex() {
+        let matcher = || PayloadMatch::Regex(Box::new(regex::Regex::new("^t=\\d+$").unwrap()));
+        assert_eq!(
+            Ok(State::Done),
+            payload_match(matcher(), &["a:1/2 t=21", "t=4"])
+        );
+        assert_eq!(
+            Err(
+                "Verification failed: 1/2 payloads did not match, first a:2/2 t=x on ze-topic not matching /^t=\\d+$/"
+                    .to_owned()
+            ),
+            payload_match(matcher(), &["a:1/2 t=21", "a:2/2 t=x"])
+        );
+    }
+
+    #[test]
+    fn payload_match_template() {
+        let template = || {
+            let template = OverlayContext::value_for(OverlayContext::root(), "{{id}}-{{seq * 10}}");
+            PayloadMatch::Template(template.unwrap())
+        };
+        assert_eq!(
+            Ok(State::Done),
+            payload_match(template(), &["a:1/2 a-10", "a:2/2 a-20"])
+        );
+        assert_eq!(
+            Err(
+                "Verification failed: 1/1 payloads did not match, first a:1/1 a-11 on ze-topic not equal to a-10"
+                    .to_owned()
+            ),
+            payload_match(template(), &["a:1/1 a-11"])
+        );
+    }
+
+    #[test]
+    fn payload_match_json_path() {
+        let matcher = || {
+            let predicate =
+                OverlayContext::expression_for(OverlayContext::root(), "value >= 20 && qos == 1");
+            PayloadMatch::JsonPath(
+                super::JsonPath::parse("$.readings[1].celsius").unwrap(),
+                predicate.unwrap(),
+            )
+        };
+        let payload = r#"a:1/1 {"readings": [{"celsius": 3}, {"celsius": 21.5}]}"#;
+        assert_eq!(Ok(State::Done), payload_match(matcher(), &[payload]));
+        assert!(payload_match(matcher(), &[r#"{"readings": []}"#])
+            .unwrap_err()
+            .ends_with(r#"first {"readings": []} on ze-topic not with $.readings[1].celsius"#));
+        assert!(payload_match(matcher(), &["not json"])
+            .unwrap_err()
+            .contains("first not json on ze-topic not JSON: "));
+        assert!(super::JsonPath::parse("readings").is_err());
+        assert!(super::JsonPath::parse("$.readings[x]").is_err());
+    }
+
+    #[test]
+    fn payload_match_expression() {
+        let matcher = || {
+            let expression = OverlayContext::expression_for(
+                OverlayContext::root(),
+                r#"topic == "ze-topic" && !retained && body == "on""#,
+            );
+            PayloadMatch::Expression(expression.unwrap())
+        };
+        assert_eq!(Ok(State::Done), payload_match(matcher(), &["a:1/1 on"]));
+        assert!(payload_match(matcher(), &["a:1/1 off"])
+            .unwrap_err()
+            .ends_with(r#"first a:1/1 off on ze-topic not satisfying topic == "ze-topic" && !retained && body == "on""#));
+    }
+
+    #[test]
+    fn payload_match_validates_against_sample_message() {
+        let template = |template| {
+            PayloadMatch::Template(
+                OverlayContext::value_for(OverlayContext::root(), template).unwrap(),
+            )
+        };
+        template("{{id}}/{{seq}}/{{body}}/{{user_origin}}")
+            .validate()
+            .unwrap();
+        assert!(template("{{ foo }}").validate().is_err());
+        let expression = |expression| {
+            PayloadMatch::Expression(
+                OverlayContext::expression_for(OverlayContext::root(), expression).unwrap(),
+            )
+        };
+        expression("qos == 1 && content_type == \"text/plain\"")
+            .validate()
+            .unwrap();
+        assert!(expression("value > 3").validate().is_err());
+    }
+
+    #[test]
+    fn payload_match_finish_and_summary() {
+        let matcher = PayloadMatch::Regex(Box::new(regex::Regex::new("on").unwrap()));
+        let mut analyzer =
+            super::PayloadMatchAnalyzer::new(matcher, Box::new(super::CountingAnalyzer::new(3)));
+        for payload in &["on", "off"] {
+            analyzer
+                .analyze(mqtt::Message::new("ze-topic", *payload, 0))
+                .unwrap();
+        }
+        assert_eq!(
+            "payload matching /on/ counting 3 messages",
+            analyzer.describe()
+        );
+        assert_eq!(Some("matched 1/2 payloads".to_owned()), analyzer.summary());
+        assert_eq!(
+            "Verification failed: received 2/3",
+            analyzer.finish().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn topic_analyzer_mapping() {
+        let published = OverlayContext::value_for(OverlayContext::root(), "a/{{seq}}").unwrap();
+        let mut topics = super::PublishedTopics::new();
+        topics.insert("1".to_owned(), Rc::new(published));
+        let expected = OverlayContext::value_for(
+            OverlayContext::root(),
+            r#"{{replace(published, "a/", "b/")}}"#,
+        )
+        .unwrap();
+        let mut analyzer =
+            super::TopicAnalyzer::new(expected, topics, Box::new(super::CountingAnalyzer::new(3)));
+        let received = [("b/1", "1:1/3"), ("a/2", "1:2/3"), ("b/3", "1:3/3")];
+        let mut state = Ok(State::Continue);
+        for (topic, payload) in &received {
+            state = analyzer.analyze(mqtt::Message::new(*topic, *payload, 0));
+        }
+        match state {
+            Err(errors::MqttVerifyError::VerificationFailure { reason }) => assert_eq!(
+                "session 1 1/3 on unexpected topics, first 1:2/3 on a/2 instead of b/2",
+                reason
+            ),
+            _ => panic!("Expected a verification failure"),
+        };
+        assert_eq!(Some("matched 2/3 topics".to_owned()), analyzer.summary());
+    }
+
+    #[test]
+    fn topic_analyzer_without_published_topic() {
+        let expected = OverlayContext::value_for(OverlayContext::root(), "s/{{id}}").unwrap();
+        let mut analyzer = super::TopicAnalyzer::new(
+            expected,
+            super::PublishedTopics::new(),
+            Box::new(super::CountingAnalyzer::new(1)),
+        );
+        assert_eq!(
+            State::Done,
+            analyzer
+                .analyze(mqtt::Message::new("s/x", "x:1/1", 0))
+                .unwrap()
+        );
+        assert!(analyzer
+            .analyze(mqtt::Message::new("s/x", "garbage", 0))
+            .is_err());
+    }
+
+    #[test]
+    fn retained_analyzer() {
+        let topic = OverlayContext::value_for(OverlayContext::root(), "s/{{seq % 2}}").unwrap();
+        let source =
+            crate::source::VerifiableSource::new("a".to_owned(), topic, 4, 1.0).with_retained(true);
+        let analyzer = || super::RetainedAnalyzer::for_sources(vec![&source].into_iter()).unwrap();
+        let mut passing = analyzer();
+        let first = mqtt::Message::new_retained("s/0", "a:4/4@1500000", 1);
+        assert_eq!(State::Continue, passing.analyze(first).unwrap());
+        let second = mqtt::Message::new_retained("s/1", "a:3/4", 1);
+        assert_eq!(State::Done, passing.analyze(second).unwrap());
+
+        let mut failing = analyzer();
+        let stale = mqtt::Message::new_retained("s/0", "a:2/4", 1);
+        assert_eq!(State::Continue, failing.analyze(stale).unwrap());
+        let live = mqtt::Message::new("s/1", "a:3/4", 1);
+        match failing.analyze(live) {
+            Err(errors::MqttVerifyError::VerificationFailure { reason }) => assert_eq!(
+                "a:2/4 on s/0 instead of a:4/4; a:3/4 on s/1 not flagged retained",
+                reason
+            ),
+            _ => panic!("Expected a verification failure"),
+        };
+    }
+
+    #[test]
+    fn retained_analyzer_reports_missing_topics() {
+        let mut expected = std::collections::BTreeMap::new();
+        expected.insert("s/0".to_owned(), vec![Marker::parse("a:2/2").unwrap()]);
+        expected.insert("s/1".to_owned(), vec![Marker::parse("b:2/2").unwrap()]);
+        let mut analyzer = super::RetainedAnalyzer::new(expected);
+        let message = mqtt::Message::new_retained("s/1", "b:2/2", 0);
+        assert_eq!(State::Continue, analyzer.analyze(message).unwrap());
+        assert_eq!(
+            Some("received retained on 1/2 topics".to_owned()),
+            analyzer.summary()
+        );
+        assert_eq!(
+            "Verification failed: nothing retained on s/0",
+            analyzer.finish().unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn will_analyzer() {
+        let will = crate::will::Will {
+            topic: "status/p-1".to_owned(),
+            payload: "gone".to_owned(),
+            qos: 1,
+            retained: false,
+        };
+        let mut expected = super::WillAnalyzer::new(will.clone(), true);
+        let other = mqtt::Message::new("status/p-1", "a:1/1", 1);
+        assert_eq!(State::Continue, expected.analyze(other).unwrap());
+        assert!(expected.finish().is_err());
+        assert_eq!(State::Done, expected.analyze(will.message()).unwrap());
+
+        let mut unexpected = super::WillAnalyzer::new(will.clone(), false);
+        assert_eq!("no will on status/p-1", unexpected.describe());
+        assert!(unexpected.finish().is_ok());
+        assert!(unexpected.analyze(will.message()).is_err());
+    }
 }
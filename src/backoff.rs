@@ -0,0 +1,81 @@
+use crate::errors::MqttVerifyError;
+use rand::{thread_rng, Rng};
+use std::cmp;
+use std::time::Duration;
+
+/// Exponential backoff between connection attempts. The delay doubles with
+/// each attempt up to `max` and is shortened by a random fraction of up to
+/// `jitter`, so that clients losing the broker together do not retry in step.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+    pub jitter: f64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff {
+            initial: Duration::from_millis(100),
+            max: Duration::from_secs(2),
+            jitter: 0.5,
+        }
+    }
+}
+
+impl Backoff {
+    /// Backoff with `jitter` between 0 and 1.
+    pub fn new(initial: Duration, max: Duration, jitter: f64) -> Result<Self, MqttVerifyError> {
+        if !(0.0..=1.0).contains(&jitter) {
+            return Err(MqttVerifyError::MalformedValue {
+                value: jitter.to_string(),
+            });
+        }
+        Ok(Backoff {
+            initial,
+            max,
+            jitter,
+        })
+    }
+
+    /// Delay after failed attempt number `attempt`, counting from 0.
+    pub fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .initial
+            .checked_mul(1 << cmp::min(attempt, 31))
+            .map_or(self.max, |delay| cmp::min(delay, self.max));
+        delay.mul_f64(1.0 - self.jitter * thread_rng().gen::<f64>())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Backoff;
+    use std::time::Duration;
+
+    #[test]
+    fn delay_doubles_up_to_max() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1), 0.0).unwrap();
+        let delays: Vec<Duration> = (0..6).map(|attempt| backoff.delay(attempt)).collect();
+        assert_eq!(
+            vec![100, 200, 400, 800, 1000, 1000],
+            delays
+                .iter()
+                .map(|delay| delay.as_millis())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Duration::from_secs(1), backoff.delay(100));
+    }
+
+    #[test]
+    fn jitter_shortens_delay() {
+        let backoff =
+            Backoff::new(Duration::from_millis(100), Duration::from_secs(1), 0.5).unwrap();
+        for _ in 0..100 {
+            let delay = backoff.delay(1);
+            assert!(delay > Duration::from_millis(100) && delay <= Duration::from_millis(200));
+        }
+        assert!(Backoff::new(Duration::from_millis(100), Duration::from_secs(1), 1.5).is_err());
+    }
+}
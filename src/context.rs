@@ -1,55 +1,231 @@
 use crate::errors::MqttVerifyError;
-use evalexpr::{build_operator_tree, Context, Function, Node, Value};
+use crate::functions;
+use evalexpr::{
+    build_operator_tree, Context, EvalexprError, EvalexprResult, Function, Node, Value,
+};
 use std::collections::HashMap;
+use std::fmt;
 use std::rc::Rc;
 
-fn maybe_push_str(parts: &mut Vec<String>, part: &str) {
-    if !part.is_empty() {
-        parts.push(format!("\"{}\"", part));
+enum Segment {
+    Literal(String),
+    Expression(Node),
+}
+
+fn write_value(out: &mut String, value: Value) -> EvalexprResult<()> {
+    match value {
+        Value::String(text) => out.push_str(&text),
+        Value::Int(_) | Value::Float(_) | Value::Boolean(_) => out.push_str(&value.to_string()),
+        value => {
+            return Err(EvalexprError::CustomMessage(format!(
+                "expected text or a number, got {}",
+                value
+            )))
+        }
+    }
+    Ok(())
+}
+
+/// Text with embedded `{{ expression }}`s. Expressions are evaluated
+/// separately and their values written as text, so numbers and strings can be
+/// mixed freely.
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn eval_string_with_context(&self, context: &dyn Context) -> EvalexprResult<String> {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Literal(text) => out.push_str(text),
+                Segment::Expression(node) => {
+                    write_value(&mut out, node.eval_with_context(context)?)?
+                }
+            }
+        }
+        Ok(out)
+    }
+
+    /// Check that all variables are defined in `context`, and that
+    /// expressions evaluate to text or numbers. Expressions calling functions
+    /// are not evaluated, as functions like `seq()` have side effects.
+    pub fn validate(&self, context: &dyn Context) -> Result<(), MqttVerifyError> {
+        for segment in &self.segments {
+            if let Segment::Expression(node) = segment {
+                if let Some(variable) = undefined_variable(node, context) {
+                    return Err(undefined(&self.source, variable));
+                }
+                if node.iter_function_identifiers().next().is_none() {
+                    node.eval_with_context(context)
+                        .and_then(|value| write_value(&mut String::new(), value))
+                        .map_err(|err| self.error(err))?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Names of the variables the expressions read.
+    pub fn variables(&self) -> Vec<String> {
+        let mut variables = Vec::new();
+        for segment in &self.segments {
+            if let Segment::Expression(node) = segment {
+                variables.extend(node.iter_variable_identifiers().map(str::to_owned));
+            }
+        }
+        variables
+    }
+
+    fn error(&self, err: EvalexprError) -> MqttVerifyError {
+        MqttVerifyError::TemplateError {
+            template: self.source.clone(),
+            reason: err.to_string(),
+        }
+    }
+}
+
+fn undefined_variable<'a>(node: &'a Node, context: &dyn Context) -> Option<&'a str> {
+    node.iter_variable_identifiers()
+        .find(|variable| context.get_value(variable).is_none())
+}
+
+fn undefined(source: &str, variable: &str) -> MqttVerifyError {
+    MqttVerifyError::TemplateError {
+        template: source.to_owned(),
+        reason: format!("undefined variable {}", variable),
     }
 }
 
-pub fn precompile(value: &str) -> Result<Node, MqttVerifyError> {
+pub fn precompile(value: &str) -> Result<Template, MqttVerifyError> {
     let mut stop = 0;
-    let mut parts: Vec<String> = Vec::new();
+    let mut segments = Vec::new();
     for (start, _) in value.match_indices("{{") {
-        maybe_push_str(&mut parts, &value[stop..start]);
+        if start < stop {
+            // Inside the previous expression
+            continue;
+        }
+        if start > stop {
+            segments.push(Segment::Literal(value[stop..start].to_owned()));
+        }
         stop = start
             + value[start..]
                 .find("}}")
                 .ok_or_else(|| MqttVerifyError::MalformedValue {
                     value: value.to_owned(),
                 })?;
-        parts.push(value[start + 2..stop].to_owned());
+        let node = build_operator_tree(&value[start + 2..stop]).map_err(|err| {
+            MqttVerifyError::MalformedExpression {
+                value: value.to_owned(),
+                source: err,
+            }
+        })?;
+        segments.push(Segment::Expression(node));
         stop += 2;
     }
-    maybe_push_str(&mut parts, &value[stop..]);
-    build_operator_tree(&parts.join("+")).map_err(|err| MqttVerifyError::MalformedExpression {
-        value: value.to_owned(),
-        source: err,
+    if stop < value.len() {
+        segments.push(Segment::Literal(value[stop..].to_owned()));
+    }
+    Ok(Template {
+        source: value.to_owned(),
+        segments,
     })
 }
 
 pub struct ContextualValue {
     context: Rc<OverlayContext>,
-    value: Node,
+    value: Template,
 }
 
 impl ContextualValue {
-    pub fn new(value: Node, context: Rc<OverlayContext>) -> Self {
+    pub fn new(value: Template, context: Rc<OverlayContext>) -> Self {
         Self { value, context }
     }
 
-    pub fn value(&self) -> String {
+    pub fn value(&self) -> Result<String, MqttVerifyError> {
         self.value
             .eval_string_with_context(self.context.as_ref())
-            .unwrap()
+            .map_err(|err| self.value.error(err))
+    }
+
+    /// Evaluate with `variables` added on top of the context, e.g. for values
+    /// that change with every message.
+    pub fn value_with(&self, variables: Vec<(String, Value)>) -> Result<String, MqttVerifyError> {
+        self.value
+            .eval_string_with_context(self.with(variables).as_ref())
+            .map_err(|err| self.value.error(err))
+    }
+
+    /// Validate the template up front, with sample values for any variables
+    /// that are only added when evaluating.
+    pub fn validate(&self, variables: Vec<(String, Value)>) -> Result<(), MqttVerifyError> {
+        self.value.validate(self.with(variables).as_ref())
+    }
+
+    pub fn variables(&self) -> Vec<String> {
+        self.value.variables()
+    }
+
+    fn with(&self, variables: Vec<(String, Value)>) -> Rc<OverlayContext> {
+        OverlayContext::with_variables(self.context.clone(), variables)
+    }
+}
+
+impl fmt::Display for ContextualValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.value.source)
+    }
+}
+
+/// A single expression, without the surrounding `{{ }}`, evaluated as a whole
+/// so its value keeps its type.
+pub struct ContextualExpression {
+    context: Rc<OverlayContext>,
+    source: String,
+    node: Node,
+}
+
+impl ContextualExpression {
+    pub fn boolean_with(&self, variables: Vec<(String, Value)>) -> Result<bool, MqttVerifyError> {
+        let context = OverlayContext::with_variables(self.context.clone(), variables);
+        self.node
+            .eval_boolean_with_context(context.as_ref())
+            .map_err(|err| MqttVerifyError::TemplateError {
+                template: self.source.clone(),
+                reason: err.to_string(),
+            })
+    }
+
+    /// Check up front that all variables are defined, with sample values for
+    /// those only added when evaluating. The expression is not evaluated, as
+    /// the samples may not satisfy it.
+    pub fn validate(&self, variables: Vec<(String, Value)>) -> Result<(), MqttVerifyError> {
+        let context = OverlayContext::with_variables(self.context.clone(), variables);
+        match undefined_variable(&self.node, context.as_ref()) {
+            Some(variable) => Err(undefined(&self.source, variable)),
+            None => Ok(()),
+        }
+    }
+
+    pub fn variables(&self) -> Vec<String> {
+        self.node
+            .iter_variable_identifiers()
+            .map(str::to_owned)
+            .collect()
+    }
+}
+
+impl fmt::Display for ContextualExpression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.source)
     }
 }
 
 pub struct OverlayContext {
     parent: Option<Rc<OverlayContext>>,
     map: HashMap<String, Value>,
+    functions: HashMap<String, Function>,
 }
 
 impl OverlayContext {
@@ -57,6 +233,7 @@ impl OverlayContext {
         Rc::new(Self {
             parent: None,
             map: HashMap::new(),
+            functions: functions::builtins(),
         })
     }
 
@@ -64,19 +241,65 @@ impl OverlayContext {
         Rc::new(Self {
             parent: Some(parent),
             map: HashMap::new(),
+            functions: HashMap::new(),
         })
     }
 
+    /// A subcontext for the values of one source, with its own `seq()`.
+    pub fn for_source(parent: Rc<OverlayContext>) -> Rc<OverlayContext> {
+        let mut context = Self::subcontext(parent);
+        Rc::get_mut(&mut context)
+            .unwrap()
+            .insert_function("seq".to_owned(), functions::seq());
+        context
+    }
+
+    /// A subcontext holding `variables`.
+    pub fn with_variables(
+        parent: Rc<OverlayContext>,
+        variables: Vec<(String, Value)>,
+    ) -> Rc<OverlayContext> {
+        let mut context = Self::subcontext(parent);
+        let inner = Rc::get_mut(&mut context).unwrap();
+        for (k, v) in variables {
+            inner.insert(k, v);
+        }
+        context
+    }
+
     pub fn insert(&mut self, key: String, val: Value) {
         self.map.insert(key, val);
     }
 
+    /// Make `function` callable by `name` from this context and its
+    /// subcontexts, shadowing any function of the same name further up.
+    pub fn insert_function(&mut self, name: String, function: Function) {
+        self.functions.insert(name, function);
+    }
+
     pub fn value_for(
         context: Rc<OverlayContext>,
         val: &str,
     ) -> Result<ContextualValue, MqttVerifyError> {
         Ok(ContextualValue::new(precompile(val)?, context))
     }
+
+    pub fn expression_for(
+        context: Rc<OverlayContext>,
+        expression: &str,
+    ) -> Result<ContextualExpression, MqttVerifyError> {
+        let node = build_operator_tree(expression).map_err(|err| {
+            MqttVerifyError::MalformedExpression {
+                value: expression.to_owned(),
+                source: err,
+            }
+        })?;
+        Ok(ContextualExpression {
+            context,
+            source: expression.to_owned(),
+            node,
+        })
+    }
 }
 
 impl Context for OverlayContext {
@@ -90,15 +313,22 @@ impl Context for OverlayContext {
         }
     }
 
-    fn get_function(&self, _: &str) -> Option<&Function> {
-        todo!()
+    fn get_function(&self, identifier: &str) -> Option<&Function> {
+        if let Some(function) = self.functions.get(identifier) {
+            Some(function)
+        } else if let Some(ref parent) = self.parent {
+            parent.get_function(identifier)
+        } else {
+            None
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::OverlayContext;
-    use evalexpr::{Context, Value};
+    use crate::errors::MqttVerifyError;
+    use evalexpr::{Context, Function, Value};
     use std::rc::Rc;
 
     #[test]
@@ -127,6 +357,78 @@ mod tests {
         );
     }
 
+    #[test]
+    fn precompile_mixes_types() {
+        let mut context = OverlayContext::root();
+        Rc::get_mut(&mut context)
+            .unwrap()
+            .insert("seq".to_owned(), Value::Int(7));
+        let template = super::precompile(r#"{"seq": {{ seq }}, "next": "{{ seq + 1 }}"}"#).unwrap();
+        assert_eq!(
+            r#"{"seq": 7, "next": "8"}"#.to_owned(),
+            template.eval_string_with_context(context.as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn value_with_variables() {
+        let value = OverlayContext::value_for(OverlayContext::root(), "n={{ n }}").unwrap();
+        assert_eq!(
+            "n=3".to_owned(),
+            value
+                .value_with(vec![("n".to_owned(), Value::Int(3))])
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn value_reports_undefined_variable() {
+        let value = OverlayContext::value_for(OverlayContext::root(), "{{ foo }}").unwrap();
+        match value.value() {
+            Err(MqttVerifyError::TemplateError { template, .. }) => {
+                assert_eq!("{{ foo }}", template)
+            }
+            _ => panic!("Expected template error"),
+        }
+    }
+
+    #[test]
+    fn validate_names_undefined_variable() {
+        let value =
+            OverlayContext::value_for(OverlayContext::root(), "a/{{ seq }}/{{ foo }}").unwrap();
+        match value.validate(vec![("seq".to_owned(), Value::Int(1))]) {
+            Err(MqttVerifyError::TemplateError { template, reason }) => {
+                assert_eq!("a/{{ seq }}/{{ foo }}", template);
+                assert_eq!("undefined variable foo", reason);
+            }
+            _ => panic!("Expected undefined variable"),
+        }
+    }
+
+    #[test]
+    fn validate_expression_names_undefined_variable() {
+        let expression =
+            OverlayContext::expression_for(OverlayContext::root(), "value > limit").unwrap();
+        assert_eq!(vec!["value", "limit"], expression.variables());
+        match expression.validate(vec![("value".to_owned(), Value::Int(1))]) {
+            Err(MqttVerifyError::TemplateError { reason, .. }) => {
+                assert_eq!("undefined variable limit", reason)
+            }
+            _ => panic!("Expected undefined variable"),
+        }
+    }
+
+    #[test]
+    fn validate_type_errors_without_calling_functions() {
+        let value = OverlayContext::value_for(OverlayContext::root(), "{{ \"a\" - 1 }}").unwrap();
+        assert!(value.validate(vec![]).is_err());
+        let value = OverlayContext::value_for(OverlayContext::root(), "{{ () }}").unwrap();
+        assert!(value.validate(vec![]).is_err());
+        let value = OverlayContext::value_for(OverlayContext::root(), "{{ seq() }}").unwrap();
+        value.validate(vec![]).unwrap();
+        assert_eq!("1", value.value().unwrap());
+    }
+
     #[test]
     fn overlay_context() {
         let mut root = OverlayContext::root();
@@ -153,4 +455,32 @@ mod tests {
             child2.get_value("foo")
         );
     }
+
+    #[test]
+    fn functions_are_inherited() {
+        let mut root = OverlayContext::root();
+        Rc::get_mut(&mut root).unwrap().insert_function(
+            "twice".to_owned(),
+            Function::new(Box::new(|argument| Ok(Value::Int(2 * argument.as_int()?)))),
+        );
+        Rc::get_mut(&mut root)
+            .unwrap()
+            .insert("name".to_owned(), Value::String("up".to_owned()));
+        let child = OverlayContext::subcontext(root);
+        let node = super::precompile("{{ twice(21) }}/{{ str::to_uppercase(name) }}").unwrap();
+        assert_eq!(
+            "42/UP".to_owned(),
+            node.eval_string_with_context(child.as_ref()).unwrap()
+        );
+    }
+
+    #[test]
+    fn expression_keeps_type() -> Result<(), MqttVerifyError> {
+        let expression = OverlayContext::expression_for(OverlayContext::root(), "qos > 0")?;
+        assert!(expression.boolean_with(vec![("qos".to_owned(), Value::Int(1))])?);
+        assert!(!expression.boolean_with(vec![("qos".to_owned(), Value::Int(0))])?);
+        assert!(expression.boolean_with(vec![]).is_err());
+        assert!(OverlayContext::expression_for(OverlayContext::root(), "(qos > 0").is_err());
+        Ok(())
+    }
 }
@@ -0,0 +1,94 @@
+use crate::context::OverlayContext;
+use crate::errors::MqttVerifyError;
+use std::fs;
+use std::rc::Rc;
+
+/// Username and password to authenticate to the broker with.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Credentials {
+    pub username: String,
+    pub password: Option<String>,
+}
+
+impl Credentials {
+    /// Expand the username and password in `context`. A password file name is
+    /// expanded too, but its contents are used as they are, less any trailing
+    /// newline.
+    pub fn expand(
+        context: Rc<OverlayContext>,
+        username: &str,
+        password: Option<&str>,
+        password_file: Option<&str>,
+    ) -> Result<Self, MqttVerifyError> {
+        let expand = |value: &str| OverlayContext::value_for(context.clone(), value)?.value();
+        let password = match (password, password_file) {
+            (Some(password), _) => Some(expand(password)?),
+            (None, Some(path)) => Some(read_password(&expand(path)?)?),
+            (None, None) => None,
+        };
+        Ok(Self {
+            username: expand(username)?,
+            password,
+        })
+    }
+}
+
+fn read_password(path: &str) -> Result<String, MqttVerifyError> {
+    let contents = fs::read_to_string(path).map_err(|err| MqttVerifyError::PasswordReadError {
+        path: path.to_owned(),
+        source: err,
+    })?;
+    Ok(contents.trim_end_matches(&['\r', '\n'][..]).to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Credentials;
+    use crate::context::OverlayContext;
+    use crate::errors::MqttVerifyError;
+    use evalexpr::Value;
+    use std::env;
+    use std::fs;
+    use std::rc::Rc;
+
+    fn context() -> Rc<OverlayContext> {
+        let mut context = OverlayContext::root();
+        Rc::get_mut(&mut context)
+            .unwrap()
+            .insert("publisher".to_owned(), Value::String("p-2".to_owned()));
+        context
+    }
+
+    #[test]
+    fn expands_username_and_password() -> Result<(), MqttVerifyError> {
+        let credentials = Credentials::expand(
+            context(),
+            "user-{{publisher}}",
+            Some("secret-{{publisher}}"),
+            None,
+        )?;
+        assert_eq!("user-p-2", credentials.username);
+        assert_eq!(Some("secret-p-2".to_owned()), credentials.password);
+        Ok(())
+    }
+
+    #[test]
+    fn reads_password_file_verbatim() -> Result<(), MqttVerifyError> {
+        let path = env::temp_dir().join("mqtt-verify-password-p-2");
+        fs::write(&path, "{{not expanded}}\n").unwrap();
+        let template = env::temp_dir().join("mqtt-verify-password-{{publisher}}");
+        let credentials =
+            Credentials::expand(context(), "user", None, Some(template.to_str().unwrap()))?;
+        fs::remove_file(&path).unwrap();
+        assert_eq!(Some("{{not expanded}}".to_owned()), credentials.password);
+        Ok(())
+    }
+
+    #[test]
+    fn missing_password_file() {
+        match Credentials::expand(context(), "user", None, Some("/nonexistent/password")) {
+            Err(MqttVerifyError::PasswordReadError { .. }) => (),
+            _ => panic!("Expected missing password file"),
+        }
+    }
+}
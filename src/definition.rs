@@ -0,0 +1,1249 @@
+use crate::analyzers;
+use crate::backoff::Backoff;
+use crate::context::OverlayContext;
+use crate::credentials;
+use crate::errors::MqttVerifyError;
+use crate::parameters;
+use crate::scenario;
+use crate::source;
+use crate::tls;
+use crate::v5;
+use crate::will;
+use evalexpr::Value;
+use regex::Regex;
+use serde::{Deserialize, Deserializer};
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::Duration;
+
+fn default_initial_timeout() -> f32 {
+    1.0
+}
+
+fn default_min_share() -> f32 {
+    0.5
+}
+
+/// Accept the MQTT version as a number like 5 or a string like "3.1.1".
+fn deserialize_mqtt_version<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    let version = match serde_json::Value::deserialize(deserializer)? {
+        serde_json::Value::String(version) => version,
+        version => version.to_string(),
+    };
+    v5::parse_version(&version).map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScenarioDefinition {
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+    /// Seconds after which the whole scenario is abandoned
+    pub deadline: Option<f32>,
+    #[serde(default)]
+    pub publishers: Vec<PublisherDefinition>,
+    #[serde(default)]
+    pub subscribers: Vec<SubscriberDefinition>,
+    /// Groups of subscribers sharing a subscription, by name
+    #[serde(default)]
+    pub groups: BTreeMap<String, GroupDefinition>,
+    /// Leave retained messages on the broker after the run
+    #[serde(default)]
+    pub keep_retained: bool,
+    /// Protocol version of all clients: 3.1, 3.1.1 or 5, defaulting to
+    /// whatever the broker accepts
+    #[serde(default, deserialize_with = "deserialize_mqtt_version")]
+    pub mqtt_version: u32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PublisherDefinition {
+    pub name: Option<String>,
+    pub uri: String,
+    #[serde(default = "default_initial_timeout")]
+    pub initial_timeout: f32,
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+    pub tls: Option<TlsDefinition>,
+    pub credentials: Option<CredentialsDefinition>,
+    pub backoff: Option<BackoffDefinition>,
+    /// Connect again when the connection is lost
+    #[serde(default)]
+    pub reconnect: bool,
+    pub will: Option<WillDefinition>,
+    /// Left to the broker when absent
+    pub client_id: Option<String>,
+    /// Drop the connection without disconnecting when done, so that the
+    /// broker publishes the will; not supported with TLS
+    #[serde(default)]
+    pub sever: bool,
+    pub sources: Vec<SourceDefinition>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WillDefinition {
+    pub topic: String,
+    #[serde(default)]
+    pub payload: String,
+    #[serde(default)]
+    pub qos: i32,
+    #[serde(default)]
+    pub retain: bool,
+}
+
+impl WillDefinition {
+    fn build(&self, context: Rc<OverlayContext>) -> Result<will::Will, MqttVerifyError> {
+        Ok(will::Will {
+            topic: expand(context.clone(), &self.topic)?,
+            payload: expand(context, &self.payload)?,
+            qos: self.qos,
+            retained: self.retain,
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SourceDefinition {
+    pub id: String,
+    pub topic: String,
+    pub count: usize,
+    pub frequency: f32,
+    #[serde(default)]
+    pub qos: i32,
+    /// Template for what follows the verification marker in each payload
+    pub payload: Option<String>,
+    #[serde(default)]
+    pub retain: bool,
+    pub properties: Option<PropertiesDefinition>,
+}
+
+/// MQTT v5 properties of every message from a source. User properties,
+/// response topic and correlation data are expanded like the topic.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PropertiesDefinition {
+    #[serde(default)]
+    pub user: BTreeMap<String, String>,
+    /// Seconds until the broker may discard the message
+    pub message_expiry: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<String>,
+    pub correlation_data: Option<String>,
+}
+
+impl PropertiesDefinition {
+    fn build(&self, context: Rc<OverlayContext>) -> Result<v5::MessageProperties, MqttVerifyError> {
+        let template = |value: &String| OverlayContext::value_for(context.clone(), value);
+        Ok(v5::MessageProperties {
+            user: self
+                .user
+                .iter()
+                .map(|(key, value)| Ok((key.clone(), template(value)?)))
+                .collect::<Result<_, MqttVerifyError>>()?,
+            message_expiry: self.message_expiry,
+            content_type: self.content_type.clone(),
+            response_topic: self.response_topic.as_ref().map(template).transpose()?,
+            correlation_data: self.correlation_data.as_ref().map(template).transpose()?,
+        })
+    }
+}
+
+/// TLS settings; file names are expanded per publisher or subscriber so each
+/// can present its own client certificate.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsDefinition {
+    pub ca_file: Option<String>,
+    pub cert_file: Option<String>,
+    pub key_file: Option<String>,
+    #[serde(default)]
+    pub insecure: bool,
+    #[serde(default)]
+    pub alpn: Vec<String>,
+}
+
+/// Username and password, both expanded per publisher or subscriber. The
+/// password may instead be read from a file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CredentialsDefinition {
+    pub username: String,
+    pub password: Option<String>,
+    pub password_file: Option<String>,
+}
+
+impl CredentialsDefinition {
+    fn build(
+        &self,
+        context: Rc<OverlayContext>,
+    ) -> Result<credentials::Credentials, MqttVerifyError> {
+        credentials::Credentials::expand(
+            context,
+            &self.username,
+            self.password.as_deref(),
+            self.password_file.as_deref(),
+        )
+    }
+}
+
+/// A subscription is either a bare topic filter, subscribed at QoS 0, or a
+/// filter with an explicit QoS, optionally shared with the other subscribers
+/// in a group.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum SubscriptionDefinition {
+    Topic(String),
+    WithQos(QosSubscriptionDefinition),
+}
+
+/// Kept apart from `SubscriptionDefinition`, as serde cannot deny unknown
+/// fields of an enum variant.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct QosSubscriptionDefinition {
+    pub topic: String,
+    #[serde(default)]
+    pub qos: i32,
+    pub share: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SubscriberDefinition {
+    pub name: Option<String>,
+    pub uri: String,
+    #[serde(default = "default_initial_timeout")]
+    pub initial_timeout: f32,
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+    pub tls: Option<TlsDefinition>,
+    pub credentials: Option<CredentialsDefinition>,
+    pub backoff: Option<BackoffDefinition>,
+    /// Connect and subscribe again when the connection is lost
+    #[serde(default)]
+    pub reconnect: bool,
+    pub topics: Vec<SubscriptionDefinition>,
+    #[serde(default)]
+    pub sinks: Vec<AnalyzerDefinition>,
+    /// Seconds to wait for the next message before giving up
+    pub idle_timeout: Option<f32>,
+    /// Connect only once all publishers are done
+    #[serde(default)]
+    pub after_publishing: bool,
+    /// Defaults to the subscriber name when the session is persistent
+    pub client_id: Option<String>,
+    pub session: Option<SessionDefinition>,
+    /// Name of the group this subscriber is a member of
+    pub group: Option<String>,
+}
+
+/// Sinks verifying what the members of a group receive between them.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GroupDefinition {
+    #[serde(default)]
+    pub parameters: HashMap<String, serde_json::Value>,
+    pub sinks: Vec<AnalyzerDefinition>,
+    /// Least fraction of an even share each member must receive
+    #[serde(default = "default_min_share")]
+    pub min_share: f32,
+    /// Seconds to wait for the next message to any member before giving up
+    pub idle_timeout: Option<f32>,
+}
+
+/// Seconds to wait between attempts to connect, doubling up to `max` and
+/// shortened by a random fraction of up to `jitter`. Defaults to those of
+/// `Backoff`.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BackoffDefinition {
+    pub initial: Option<f32>,
+    pub max: Option<f32>,
+    pub jitter: Option<f64>,
+}
+
+impl BackoffDefinition {
+    fn build(&self) -> Result<Backoff, MqttVerifyError> {
+        let default = Backoff::default();
+        Backoff::new(
+            self.initial
+                .map_or(default.initial, Duration::from_secs_f32),
+            self.max.map_or(default.max, Duration::from_secs_f32),
+            self.jitter.unwrap_or(default.jitter),
+        )
+    }
+}
+
+fn build_backoff(definition: &Option<BackoffDefinition>) -> Result<Backoff, MqttVerifyError> {
+    definition
+        .as_ref()
+        .map_or_else(|| Ok(Backoff::default()), BackoffDefinition::build)
+}
+
+/// A persistent session the subscriber drops out of for a while.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SessionDefinition {
+    /// Seconds after subscribing to disconnect
+    pub offline_after: f32,
+    /// Seconds to stay disconnected
+    pub offline_for: f32,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum AnalyzerDefinition {
+    Counting {
+        count: usize,
+    },
+    Sequence {
+        sessions: usize,
+        #[serde(default)]
+        qos: i32,
+    },
+    Latency {
+        count: usize,
+        max_p50: Option<f32>,
+        max_p95: Option<f32>,
+        max_p99: Option<f32>,
+        max_latency: Option<f32>,
+    },
+    SessionId {
+        id: String,
+        child: Box<AnalyzerDefinition>,
+    },
+    /// Topic expanded with the message variables, and `published` for the
+    /// topic it was published to
+    Topic {
+        expected: String,
+        child: Box<AnalyzerDefinition>,
+    },
+    /// The last retained message on every topic of retained sources
+    Retained {},
+    /// The will of the named publisher, expected if and only if it severs
+    /// its connection
+    Will {
+        publisher: String,
+    },
+    /// Exactly one of regex, template, json_path with predicate, or expression
+    Payload {
+        regex: Option<String>,
+        template: Option<String>,
+        json_path: Option<String>,
+        predicate: Option<String>,
+        expression: Option<String>,
+        child: Box<AnalyzerDefinition>,
+    },
+}
+
+fn subcontext(
+    parent: Rc<OverlayContext>,
+    role: &str,
+    name: String,
+    parameters: &HashMap<String, serde_json::Value>,
+) -> Result<Rc<OverlayContext>, MqttVerifyError> {
+    let mut context = OverlayContext::subcontext(parent);
+    let inner = Rc::get_mut(&mut context).unwrap();
+    inner.insert(role.to_owned(), Value::String(name));
+    for (k, v) in parameters {
+        inner.insert(k.clone(), parameters::from_json(v)?);
+    }
+    Ok(context)
+}
+
+fn expand(context: Rc<OverlayContext>, value: &str) -> Result<String, MqttVerifyError> {
+    OverlayContext::value_for(context, value)?.value()
+}
+
+/// Use TLS when configured or when the URI requires it.
+fn build_tls(
+    context: Rc<OverlayContext>,
+    uri: &str,
+    definition: &Option<TlsDefinition>,
+) -> Result<Option<tls::TlsOptions>, MqttVerifyError> {
+    let definition = match definition {
+        Some(definition) => definition,
+        None if tls::TlsOptions::required_by(uri) => return Ok(Some(Default::default())),
+        None => return Ok(None),
+    };
+    let path = |file: &Option<String>| -> Result<Option<PathBuf>, MqttVerifyError> {
+        file.as_ref()
+            .map(|file| Ok(PathBuf::from(expand(context.clone(), file)?)))
+            .transpose()
+    };
+    Ok(Some(tls::TlsOptions {
+        ca_file: path(&definition.ca_file)?,
+        cert_file: path(&definition.cert_file)?,
+        key_file: path(&definition.key_file)?,
+        insecure: definition.insecure,
+        alpn: definition.alpn.clone(),
+    }))
+}
+
+impl ScenarioDefinition {
+    pub fn from_file(path: &Path) -> Result<Self, MqttVerifyError> {
+        let name = path.display().to_string();
+        let contents =
+            fs::read_to_string(path).map_err(|err| MqttVerifyError::ScenarioReadError {
+                path: name.clone(),
+                source: err,
+            })?;
+        Self::parse(&name, &contents)
+    }
+
+    /// Parse a scenario, picking YAML or TOML based on the extension of `path`.
+    pub fn parse(path: &str, contents: &str) -> Result<Self, MqttVerifyError> {
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(contents).map_err(|err| MqttVerifyError::ScenarioYamlError {
+                    path: path.to_owned(),
+                    source: err,
+                })
+            }
+            Some("toml") => {
+                toml::from_str(contents).map_err(|err| MqttVerifyError::ScenarioTomlError {
+                    path: path.to_owned(),
+                    source: err,
+                })
+            }
+            _ => Err(MqttVerifyError::UnknownScenarioFormat {
+                path: path.to_owned(),
+            }),
+        }
+    }
+
+    /// Compile the definition into a runnable scenario. Parameters given here
+    /// take precedence over those in the file.
+    pub fn build(
+        &self,
+        parameters: &[(String, Value)],
+    ) -> Result<scenario::Scenario, MqttVerifyError> {
+        let mut root = OverlayContext::root();
+        for (k, v) in &self.parameters {
+            Rc::get_mut(&mut root)
+                .unwrap()
+                .insert(k.clone(), parameters::from_json(v)?);
+        }
+        for (k, v) in parameters {
+            Rc::get_mut(&mut root).unwrap().insert(k.clone(), v.clone());
+        }
+        let publishers = self
+            .publishers
+            .iter()
+            .enumerate()
+            .map(|(i, definition)| definition.build(root.clone(), i + 1, self.mqtt_version))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut groups = self
+            .groups
+            .iter()
+            .map(|(name, definition)| definition.build(root.clone(), name, &publishers))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut subscribers = Vec::new();
+        for (i, definition) in self.subscribers.iter().enumerate() {
+            let subscriber =
+                definition.build(root.clone(), i + 1, self.mqtt_version, &publishers)?;
+            let name = match definition.group {
+                Some(ref name) => name,
+                None => {
+                    subscribers.push(subscriber);
+                    continue;
+                }
+            };
+            let malformed = |reason: &str| MqttVerifyError::MalformedGroup {
+                group: name.clone(),
+                reason: format!("{} {}", subscriber.name, reason),
+            };
+            if subscriber.session.is_some() {
+                return Err(malformed("has a persistent session"));
+            }
+            if subscriber.after_publishing {
+                return Err(malformed("waits for the publishers"));
+            }
+            match groups.iter_mut().find(|group| &group.name == name) {
+                Some(group) => group.members.push(subscriber),
+                None => return Err(malformed("is a member of an undefined group")),
+            }
+        }
+        if let Some(group) = groups.iter().find(|group| group.members.is_empty()) {
+            return Err(MqttVerifyError::MalformedGroup {
+                group: group.name.clone(),
+                reason: "no members".to_owned(),
+            });
+        }
+        let scenario = scenario::Scenario {
+            publishers,
+            subscribers,
+            groups,
+            deadline: self.deadline.map(Duration::from_secs_f32),
+            keep_retained: self.keep_retained,
+        };
+        scenario.validate()?;
+        Ok(scenario)
+    }
+}
+
+impl PublisherDefinition {
+    fn build(
+        &self,
+        root: Rc<OverlayContext>,
+        index: usize,
+        mqtt_version: u32,
+    ) -> Result<scenario::Publisher, MqttVerifyError> {
+        let name = self.name.clone().unwrap_or_else(|| format!("p-{}", index));
+        let context = subcontext(root, "publisher", name.clone(), &self.parameters)?;
+        let sources = self
+            .sources
+            .iter()
+            .map(|source| {
+                let values = OverlayContext::for_source(context.clone());
+                let mut built = source::VerifiableSource::new(
+                    expand(context.clone(), &source.id)?,
+                    OverlayContext::value_for(values.clone(), &source.topic)?,
+                    source.count,
+                    source.frequency,
+                )
+                .with_qos(source.qos)
+                .with_retained(source.retain);
+                if let Some(ref payload) = source.payload {
+                    built = built.with_payload(OverlayContext::value_for(values.clone(), payload)?);
+                }
+                if let Some(ref properties) = source.properties {
+                    built = built.with_properties(properties.build(values)?);
+                }
+                built.validate()?;
+                Ok(built)
+            })
+            .collect::<Result<Vec<_>, MqttVerifyError>>()?;
+        let uri = expand(context.clone(), &self.uri)?;
+        let tls = build_tls(context.clone(), &uri, &self.tls)?;
+        let relay = match (self.sever, &tls) {
+            (false, _) => None,
+            (true, None) => Some(will::Relay::bind(&uri)?),
+            (true, Some(_)) => return Err(MqttVerifyError::SeverOverTls { uri }),
+        };
+        let client_id = match self.client_id {
+            Some(ref client_id) => expand(context.clone(), client_id)?,
+            None => String::new(),
+        };
+        Ok(scenario::Publisher {
+            name,
+            client: crate::create_client(
+                relay.as_ref().map_or(&uri, |relay| relay.uri()),
+                &client_id,
+                mqtt_version,
+            ),
+            initial_timeout: Duration::from_secs_f32(self.initial_timeout),
+            tls,
+            credentials: self
+                .credentials
+                .as_ref()
+                .map(|credentials| credentials.build(context.clone()))
+                .transpose()?,
+            backoff: build_backoff(&self.backoff)?,
+            reconnect: self.reconnect,
+            will: self
+                .will
+                .as_ref()
+                .map(|will| will.build(context))
+                .transpose()?,
+            relay,
+            sources,
+        })
+    }
+}
+
+impl SubscriberDefinition {
+    fn build(
+        &self,
+        root: Rc<OverlayContext>,
+        index: usize,
+        mqtt_version: u32,
+        publishers: &[scenario::Publisher],
+    ) -> Result<scenario::Subscriber, MqttVerifyError> {
+        let name = self.name.clone().unwrap_or_else(|| format!("s-{}", index));
+        let context = subcontext(root, "subscriber", name.clone(), &self.parameters)?;
+        let topics = self
+            .topics
+            .iter()
+            .map(|subscription| {
+                let (topic, qos, share) = match subscription {
+                    SubscriptionDefinition::Topic(topic) => (topic, 0, None),
+                    SubscriptionDefinition::WithQos(subscription) => (
+                        &subscription.topic,
+                        subscription.qos,
+                        subscription.share.as_ref(),
+                    ),
+                };
+                let topic = expand(context.clone(), topic)?;
+                Ok(scenario::Subscription {
+                    topic: match share {
+                        Some(group) => v5::shared(&expand(context.clone(), group)?, &topic),
+                        None => topic,
+                    },
+                    qos,
+                })
+            })
+            .collect::<Result<Vec<_>, MqttVerifyError>>()?;
+        let sinks = self
+            .sinks
+            .iter()
+            .map(|sink| sink.build(context.clone(), publishers))
+            .collect::<Result<Vec<_>, _>>()?;
+        let uri = expand(context.clone(), &self.uri)?;
+        let client_id = match (&self.client_id, &self.session) {
+            (Some(client_id), _) => expand(context.clone(), client_id)?,
+            (None, Some(_)) => name.clone(),
+            (None, None) => String::new(),
+        };
+        Ok(scenario::Subscriber {
+            name,
+            client: crate::create_client(&uri, &client_id, mqtt_version),
+            initial_timeout: Duration::from_secs_f32(self.initial_timeout),
+            tls: build_tls(context.clone(), &uri, &self.tls)?,
+            credentials: self
+                .credentials
+                .as_ref()
+                .map(|credentials| credentials.build(context))
+                .transpose()?,
+            backoff: build_backoff(&self.backoff)?,
+            reconnect: self.reconnect,
+            topics,
+            sinks,
+            idle_timeout: self.idle_timeout.map(Duration::from_secs_f32),
+            after_publishing: self.after_publishing,
+            session: self
+                .session
+                .as_ref()
+                .map(|session| scenario::PersistentSession {
+                    offline_after: Duration::from_secs_f32(session.offline_after),
+                    offline_for: Duration::from_secs_f32(session.offline_for),
+                }),
+        })
+    }
+}
+
+impl GroupDefinition {
+    fn build(
+        &self,
+        root: Rc<OverlayContext>,
+        name: &str,
+        publishers: &[scenario::Publisher],
+    ) -> Result<scenario::SubscriberGroup, MqttVerifyError> {
+        let context = subcontext(root, "group", name.to_owned(), &self.parameters)?;
+        Ok(scenario::SubscriberGroup {
+            name: name.to_owned(),
+            members: Vec::new(),
+            sinks: self
+                .sinks
+                .iter()
+                .map(|sink| sink.build(context.clone(), publishers))
+                .collect::<Result<Vec<_>, _>>()?,
+            min_share: self.min_share as f64,
+            idle_timeout: self.idle_timeout.map(Duration::from_secs_f32),
+        })
+    }
+}
+
+impl AnalyzerDefinition {
+    fn build(
+        &self,
+        context: Rc<OverlayContext>,
+        publishers: &[scenario::Publisher],
+    ) -> Result<Box<dyn analyzers::Analyzer>, MqttVerifyError> {
+        Ok(match self {
+            AnalyzerDefinition::Counting { count } => {
+                Box::new(analyzers::CountingAnalyzer::new(*count))
+            }
+            AnalyzerDefinition::Sequence { sessions, qos } => Box::new(
+                analyzers::SequenceAnalyzer::new(*sessions, analyzers::Delivery::for_qos(*qos)),
+            ),
+            AnalyzerDefinition::Latency {
+                count,
+                max_p50,
+                max_p95,
+                max_p99,
+                max_latency,
+            } => Box::new(analyzers::LatencyAnalyzer::new(
+                *count,
+                analyzers::LatencyThresholds {
+                    p50: max_p50.map(Duration::from_secs_f32),
+                    p95: max_p95.map(Duration::from_secs_f32),
+                    p99: max_p99.map(Duration::from_secs_f32),
+                    max: max_latency.map(Duration::from_secs_f32),
+                },
+            )),
+            AnalyzerDefinition::SessionId { id, child } => {
+                Box::new(analyzers::SessionIdFilter::new(
+                    expand(context.clone(), id)?,
+                    child.build(context, publishers)?,
+                ))
+            }
+            AnalyzerDefinition::Topic { expected, child } => {
+                let analyzer = analyzers::TopicAnalyzer::new(
+                    OverlayContext::value_for(context.clone(), expected)?,
+                    publishers
+                        .iter()
+                        .flat_map(|publisher| publisher.sources.iter())
+                        .map(|source| (source.id().to_owned(), source.topic.clone()))
+                        .collect(),
+                    child.build(context, publishers)?,
+                );
+                analyzer.validate()?;
+                Box::new(analyzer)
+            }
+            AnalyzerDefinition::Retained {} => Box::new(analyzers::RetainedAnalyzer::for_sources(
+                publishers
+                    .iter()
+                    .flat_map(|publisher| publisher.sources.iter()),
+            )?),
+            AnalyzerDefinition::Will { publisher } => {
+                let name = expand(context, publisher)?;
+                let (will, severs) = publishers
+                    .iter()
+                    .find(|candidate| candidate.name == name)
+                    .and_then(|publisher| {
+                        let severs = publisher.relay.is_some();
+                        publisher.will.clone().map(|will| (will, severs))
+                    })
+                    .ok_or_else(|| MqttVerifyError::MalformedValue {
+                        value: format!("no publisher {} with a will", name),
+                    })?;
+                Box::new(analyzers::WillAnalyzer::new(will, severs))
+            }
+            AnalyzerDefinition::Payload {
+                regex,
+                template,
+                json_path,
+                predicate,
+                expression,
+                child,
+            } => {
+                let matcher = payload_match(
+                    context.clone(),
+                    regex,
+                    template,
+                    json_path,
+                    predicate,
+                    expression,
+                )?;
+                matcher.validate()?;
+                Box::new(analyzers::PayloadMatchAnalyzer::new(
+                    matcher,
+                    child.build(context, publishers)?,
+                ))
+            }
+        })
+    }
+}
+
+fn payload_match(
+    context: Rc<OverlayContext>,
+    regex: &Option<String>,
+    template: &Option<String>,
+    json_path: &Option<String>,
+    predicate: &Option<String>,
+    expression: &Option<String>,
+) -> Result<analyzers::PayloadMatch, MqttVerifyError> {
+    Ok(match (regex, template, json_path, predicate, expression) {
+        (Some(regex), None, None, None, None) => {
+            let regex = expand(context, regex)?;
+            analyzers::PayloadMatch::Regex(Box::new(Regex::new(&regex).map_err(|err| {
+                MqttVerifyError::MalformedRegex {
+                    value: regex.clone(),
+                    source: err,
+                }
+            })?))
+        }
+        (None, Some(template), None, None, None) => {
+            analyzers::PayloadMatch::Template(OverlayContext::value_for(context, template)?)
+        }
+        (None, None, Some(path), Some(predicate), None) => analyzers::PayloadMatch::JsonPath(
+            analyzers::JsonPath::parse(&expand(context.clone(), path)?)?,
+            OverlayContext::expression_for(context, predicate)?,
+        ),
+        (None, None, None, None, Some(expression)) => analyzers::PayloadMatch::Expression(
+            OverlayContext::expression_for(context, expression)?,
+        ),
+        _ => {
+            return Err(MqttVerifyError::MalformedValue {
+                value: "payload sink needs one of regex, template, json_path with predicate \
+                        or expression"
+                    .to_owned(),
+            })
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ScenarioDefinition;
+    use crate::analyzers::State;
+    use crate::backoff::Backoff;
+    use crate::errors::MqttVerifyError;
+    use evalexpr::Value;
+    use paho_mqtt as mqtt;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    const YAML: &str = r#"
+parameters:
+  prefix: verify
+deadline: 30
+publishers:
+  - uri: tcp://localhost:1883
+    credentials:
+      username: "user-{{publisher}}"
+      password: "{{prefix}}"
+    sources:
+      - id: "{{publisher}}"
+        topic: "{{prefix}}/{{publisher}}"
+        count: 10
+        frequency: 2.0
+        payload: '{"from": "{{publisher}}", "seq": {{seq}}}'
+subscribers:
+  - uri: ssl://localhost:8883
+    initial_timeout: 2.5
+    idle_timeout: 5
+    tls:
+      ca_file: ca.pem
+      cert_file: "{{subscriber}}.crt"
+      alpn: [mqtt]
+    topics:
+      - "{{prefix}}/#"
+      - topic: control
+        qos: 1
+    sinks:
+      - type: session_id
+        id: p-1
+        child:
+          type: counting
+          count: 10
+      - type: topic
+        expected: "{{published}}"
+        child:
+          type: counting
+          count: 10
+      - type: payload
+        json_path: $.from
+        predicate: value == "p-1"
+        child:
+          type: counting
+          count: 10
+"#;
+
+    const TOML: &str = r#"
+[parameters]
+shards = 4
+[[publishers]]
+name = "alpha"
+uri = "tcp://localhost:1883"
+[[publishers.sources]]
+id = "1"
+topic = "{{publisher}}/{{suffix}}/{{shards * 2}}"
+count = 3
+frequency = 1.0
+"#;
+
+    #[test]
+    fn parse_yaml() -> Result<(), MqttVerifyError> {
+        let definition = ScenarioDefinition::parse("scenario.yaml", YAML)?;
+        assert_eq!(1, definition.publishers.len());
+        assert_eq!(1, definition.subscribers.len());
+        assert_eq!(2.5, definition.subscribers[0].initial_timeout);
+        Ok(())
+    }
+
+    #[test]
+    fn build_expands_expressions() -> Result<(), MqttVerifyError> {
+        let scenario = ScenarioDefinition::parse("scenario.yml", YAML)?.build(&[])?;
+        let source = &scenario.publishers[0].sources[0];
+        assert_eq!("verify/p-1".to_owned(), source.topic.value()?);
+        let payload = source.next_message().unwrap()?.payload_str().into_owned();
+        assert!(payload.ends_with(r#" {"from": "p-1", "seq": 1}"#));
+        let topics = &scenario.subscribers[0].topics;
+        assert_eq!(2, topics.len());
+        assert_eq!(("verify/#", 0), (topics[0].topic.as_str(), topics[0].qos));
+        assert_eq!(("control", 1), (topics[1].topic.as_str(), topics[1].qos));
+        assert_eq!(3, scenario.subscribers[0].sinks.len());
+        assert_eq!(
+            "payload with $.from value == \"p-1\" counting 10 messages",
+            scenario.subscribers[0].sinks[2].describe()
+        );
+        assert_eq!(
+            Some(Duration::from_secs(5)),
+            scenario.subscribers[0].idle_timeout
+        );
+        assert_eq!(Some(Duration::from_secs(30)), scenario.deadline);
+        let tls = scenario.subscribers[0].tls.as_ref().unwrap();
+        assert_eq!(Some(PathBuf::from("s-1.crt")), tls.cert_file);
+        assert_eq!(vec!["mqtt".to_owned()], tls.alpn);
+        assert!(scenario.publishers[0].tls.is_none());
+        let credentials = scenario.publishers[0].credentials.as_ref().unwrap();
+        assert_eq!("user-p-1", credentials.username);
+        assert_eq!(Some("verify".to_owned()), credentials.password);
+        Ok(())
+    }
+
+    #[test]
+    fn build_toml_with_overriding_parameter() -> Result<(), MqttVerifyError> {
+        let parameters = vec![("suffix".to_owned(), Value::String("temp".to_owned()))];
+        let scenario = ScenarioDefinition::parse("scenario.toml", TOML)?.build(&parameters)?;
+        let source = &scenario.publishers[0].sources[0];
+        assert_eq!("alpha/temp/8".to_owned(), source.topic.value()?);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_rejects_unknown_subscription_fields() {
+        let yaml = YAML.replace("qos: 1", "qso: 1");
+        assert_ne!(YAML, yaml);
+        assert!(ScenarioDefinition::parse("scenario.yaml", &yaml).is_err());
+    }
+
+    #[test]
+    fn parse_unknown_format() {
+        match ScenarioDefinition::parse("scenario.json", "{}") {
+            Err(MqttVerifyError::UnknownScenarioFormat { .. }) => (),
+            _ => panic!("Expected unknown format"),
+        }
+    }
+
+    #[test]
+    fn topic_sink_knows_published_topics() -> Result<(), MqttVerifyError> {
+        let mut scenario = ScenarioDefinition::parse("scenario.yaml", YAML)?.build(&[])?;
+        let message = scenario.publishers[0].sources[0].next_message().unwrap()?;
+        let topic_sink = &mut scenario.subscribers[0].sinks[1];
+        assert_eq!(State::Continue, topic_sink.analyze(message.clone())?);
+        let moved = mqtt::Message::new("elsewhere", message.payload(), 0);
+        assert_eq!(State::Continue, topic_sink.analyze(moved)?);
+        assert!(topic_sink
+            .summary()
+            .unwrap()
+            .starts_with("matched 1/2 topics"));
+        Ok(())
+    }
+
+    #[test]
+    fn build_retained() -> Result<(), MqttVerifyError> {
+        let yaml = r#"
+keep_retained: true
+publishers:
+  - uri: tcp://localhost:1883
+    sources:
+      - id: status
+        topic: "status/{{seq % 3}}"
+        count: 6
+        frequency: 10.0
+        retain: true
+subscribers:
+  - uri: tcp://localhost:1883
+    after_publishing: true
+    topics: [status/+]
+    sinks:
+      - type: retained
+"#;
+        let scenario = ScenarioDefinition::parse("scenario.yaml", yaml)?.build(&[])?;
+        assert!(scenario.keep_retained);
+        assert!(scenario.subscribers[0].after_publishing);
+        assert_eq!(
+            vec!["status/0", "status/1", "status/2"],
+            scenario.publishers[0].retained_topics()?
+        );
+        assert_eq!(
+            "retained on 3 topics",
+            scenario.subscribers[0].sinks[0].describe()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn build_will() -> Result<(), MqttVerifyError> {
+        let yaml = r#"
+publishers:
+  - name: doomed
+    uri: tcp://localhost:1883
+    will:
+      topic: "status/{{publisher}}"
+      payload: gone
+      qos: 1
+    sever: true
+    sources: []
+  - uri: tcp://localhost:1883
+    will:
+      topic: "status/{{publisher}}"
+    sources: []
+subscribers:
+  - uri: tcp://localhost:1883
+    topics: [status/+]
+    sinks:
+      - type: will
+        publisher: doomed
+      - type: will
+        publisher: p-2
+"#;
+        let scenario = ScenarioDefinition::parse("scenario.yaml", yaml)?.build(&[])?;
+        let doomed = &scenario.publishers[0];
+        let will = doomed.will.as_ref().unwrap();
+        assert_eq!(
+            ("status/doomed", "gone", 1),
+            (will.topic.as_str(), will.payload.as_str(), will.qos)
+        );
+        assert!(crate::server_uri(&doomed.client).starts_with("tcp://127.0.0.1:"));
+        assert!(scenario.publishers[1].relay.is_none());
+        let sinks = &scenario.subscribers[0].sinks;
+        assert_eq!("will on status/doomed", sinks[0].describe());
+        assert_eq!("no will on status/p-2", sinks[1].describe());
+        Ok(())
+    }
+
+    #[test]
+    fn build_persistent_session() -> Result<(), MqttVerifyError> {
+        let yaml = r#"
+subscribers:
+  - name: durable
+    uri: tcp://localhost:1883
+    topics:
+      - topic: verify/#
+        qos: 1
+    session:
+      offline_after: 2
+      offline_for: 0.5
+    sinks:
+      - type: sequence
+        sessions: 1
+        qos: 1
+"#;
+        let scenario = ScenarioDefinition::parse("scenario.yaml", yaml)?.build(&[])?;
+        let subscriber = &scenario.subscribers[0];
+        assert_eq!("durable", crate::client_id(&subscriber.client));
+        let session = subscriber.session.as_ref().unwrap();
+        assert_eq!(Duration::from_secs(2), session.offline_after);
+        assert_eq!(Duration::from_millis(500), session.offline_for);
+        Ok(())
+    }
+
+    #[test]
+    fn build_v5() -> Result<(), MqttVerifyError> {
+        let yaml = r#"
+mqtt_version: 5
+publishers:
+  - uri: tcp://localhost:1883
+    sources:
+      - id: "{{publisher}}"
+        topic: requests
+        count: 1
+        frequency: 1.0
+        properties:
+          user:
+            origin: "{{publisher}}"
+          message_expiry: 30
+          response_topic: "replies/{{id}}"
+subscribers:
+  - uri: tcp://localhost:1883
+    topics:
+      - topic: requests
+        share: workers
+    sinks:
+      - type: payload
+        expression: user_origin == "p-1" && message_expiry == 30
+        child:
+          type: counting
+          count: 1
+"#;
+        let mut scenario = ScenarioDefinition::parse("scenario.yaml", yaml)?.build(&[])?;
+        assert_eq!(
+            mqtt::MQTT_VERSION_5,
+            scenario.publishers[0].client.mqtt_version()
+        );
+        let subscriber = &mut scenario.subscribers[0];
+        assert_eq!(mqtt::MQTT_VERSION_5, subscriber.client.mqtt_version());
+        assert_eq!(
+            ("$share/workers/requests", 0),
+            (
+                subscriber.topics[0].topic.as_str(),
+                subscriber.topics[0].qos
+            )
+        );
+        let message = scenario.publishers[0].sources[0].next_message().unwrap()?;
+        assert_eq!(State::Done, subscriber.sinks[0].analyze(message)?);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_mqtt_version() -> Result<(), MqttVerifyError> {
+        let toml = "mqtt_version = \"3.1.1\"\n";
+        let definition = ScenarioDefinition::parse("scenario.toml", toml)?;
+        assert_eq!(mqtt::MQTT_VERSION_3_1_1, definition.mqtt_version);
+        let definition = ScenarioDefinition::parse("scenario.yaml", "{}")?;
+        assert_eq!(mqtt::MQTT_VERSION_DEFAULT, definition.mqtt_version);
+        assert!(ScenarioDefinition::parse("scenario.yaml", "mqtt_version: 4").is_err());
+        Ok(())
+    }
+
+    const GROUP: &str = r#"
+groups:
+  workers:
+    min_share: 0.25
+    idle_timeout: 3
+    sinks:
+      - type: sequence
+        sessions: 1
+        qos: 2
+subscribers:
+  - uri: tcp://localhost:1883
+    group: workers
+    topics:
+      - topic: verify/#
+        qos: 1
+        share: workers
+  - uri: tcp://localhost:1883
+    group: workers
+    topics:
+      - topic: verify/#
+        qos: 1
+        share: workers
+    sinks:
+      - type: counting
+        count: 10
+  - uri: tcp://localhost:1883
+    topics: [verify/#]
+    sinks: []
+"#;
+
+    #[test]
+    fn build_group() -> Result<(), MqttVerifyError> {
+        let scenario = ScenarioDefinition::parse("scenario.yaml", GROUP)?.build(&[])?;
+        assert_eq!(1, scenario.subscribers.len());
+        assert_eq!("s-3", scenario.subscribers[0].name);
+        let group = &scenario.groups[0];
+        assert_eq!("workers", group.name);
+        let members: Vec<&str> = group.members.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(vec!["s-1", "s-2"], members);
+        assert_eq!("$share/workers/verify/#", group.members[0].topics[0].topic);
+        assert_eq!(1, group.members[1].sinks.len());
+        assert_eq!(0.25, group.min_share);
+        assert_eq!(Some(Duration::from_secs(3)), group.idle_timeout);
+        assert_eq!(1, group.sinks.len());
+        Ok(())
+    }
+
+    #[test]
+    fn group_members_must_join_a_defined_group() {
+        let yaml = GROUP.replace("group: workers\n    topics", "group: idlers\n    topics");
+        match ScenarioDefinition::parse("scenario.yaml", &yaml).and_then(|d| d.build(&[])) {
+            Err(MqttVerifyError::MalformedGroup { group, reason }) => {
+                assert_eq!("idlers", group);
+                assert_eq!("s-1 is a member of an undefined group", reason);
+            }
+            _ => panic!("Expected a malformed group"),
+        }
+    }
+
+    #[test]
+    fn build_backoff_and_reconnect() -> Result<(), MqttVerifyError> {
+        let yaml = r#"
+publishers:
+  - uri: tcp://localhost:1883
+    reconnect: true
+    backoff:
+      initial: 0.5
+      jitter: 0
+    sources: []
+subscribers:
+  - uri: tcp://localhost:1883
+    topics: [verify]
+"#;
+        let scenario = ScenarioDefinition::parse("scenario.yaml", yaml)?.build(&[])?;
+        let publisher = &scenario.publishers[0];
+        assert!(publisher.reconnect);
+        assert_eq!(
+            Backoff::new(Duration::from_millis(500), Duration::from_secs(2), 0.0)?,
+            publisher.backoff
+        );
+        assert!(!scenario.subscribers[0].reconnect);
+        assert_eq!(Backoff::default(), scenario.subscribers[0].backoff);
+        Ok(())
+    }
+
+    const CLIENT_IDS: &str = r#"
+publishers:
+  - uri: tcp://localhost:1883
+    client_id: "verify-{{publisher}}"
+    sources: []
+subscribers:
+  - uri: tcp://localhost:1883
+    client_id: "verify-{{subscriber}}"
+    topics: [verify]
+"#;
+
+    #[test]
+    fn build_client_ids() -> Result<(), MqttVerifyError> {
+        let scenario = ScenarioDefinition::parse("scenario.yaml", CLIENT_IDS)?.build(&[])?;
+        assert_eq!(
+            "verify-p-1",
+            crate::client_id(&scenario.publishers[0].client)
+        );
+        assert_eq!(
+            "verify-s-1",
+            crate::client_id(&scenario.subscribers[0].client)
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn client_ids_must_be_unique() {
+        let yaml = CLIENT_IDS.replace("verify-{{subscriber}}", "verify-p-1");
+        match ScenarioDefinition::parse("scenario.yaml", &yaml).and_then(|d| d.build(&[])) {
+            Err(MqttVerifyError::DuplicateClientId {
+                client_id,
+                name,
+                first,
+            }) => {
+                assert_eq!("verify-p-1", client_id);
+                assert_eq!("s-1", name);
+                assert_eq!("p-1", first);
+            }
+            _ => panic!("Expected a duplicate client id"),
+        }
+    }
+
+    #[test]
+    fn build_rejects_undefined_variables() {
+        let undefined = |yaml: &str| match ScenarioDefinition::parse("scenario.yaml", yaml)
+            .and_then(|d| d.build(&[]))
+        {
+            Err(MqttVerifyError::TemplateError { reason, .. }) => {
+                assert_eq!("undefined variable foo", reason)
+            }
+            _ => panic!("Expected an undefined variable"),
+        };
+        undefined(&YAML.replace(r#""seq": {{seq}}"#, r#""seq": {{ foo }}"#));
+        undefined(&YAML.replace("expected: \"{{published}}\"", "expected: \"{{ foo }}\""));
+        undefined(&YAML.replace(r#"value == "p-1""#, r#"value == foo"#));
+    }
+
+    #[test]
+    fn payload_sink_requires_one_check() {
+        let yaml = r#"
+subscribers:
+  - uri: tcp://localhost:1883
+    topics: [verify]
+    sinks:
+      - type: payload
+        regex: "^on$"
+        expression: retained
+        child:
+          type: counting
+          count: 1
+"#;
+        match ScenarioDefinition::parse("scenario.yaml", yaml).and_then(|d| d.build(&[])) {
+            Err(MqttVerifyError::MalformedValue { .. }) => (),
+            _ => panic!("Expected a malformed payload sink"),
+        }
+    }
+}
@@ -8,12 +8,51 @@ pub enum MqttVerifyError {
     SourceTimerError { source: std::io::Error },
     #[snafu(display("Connect borked: {}", source))]
     MqttConnectError { source: paho_mqtt::errors::Error },
+    #[snafu(display("TLS handshake with {} borked: {}", uri, source))]
+    TlsHandshakeError {
+        uri: String,
+        source: paho_mqtt::errors::Error,
+    },
+    #[snafu(display("Reading TLS file {} borked: {}", path, source))]
+    TlsFileError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Malformed TLS file name {}: {}", path, source))]
+    TlsOptionError {
+        path: String,
+        source: paho_mqtt::errors::Error,
+    },
+    #[snafu(display("Reading password file {} borked: {}", path, source))]
+    PasswordReadError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display(
+        "Severing TLS connections to {} is not supported, the broker certificate would not match the relay",
+        uri
+    ))]
+    SeverOverTls { uri: String },
+    #[snafu(display("Relay to broker borked: {}", source))]
+    RelayError { source: std::io::Error },
     #[snafu(display("Disconnect borked: {}", source))]
     MqttDisconnectError { source: paho_mqtt::errors::Error },
     #[snafu(display("Publish borked: {}", source))]
     MqttPublishError { source: paho_mqtt::errors::Error },
     #[snafu(display("Subscribe borked: {}", source))]
     MqttSubscribeError { source: paho_mqtt::errors::Error },
+    #[snafu(display("Connect refused with reason code 0x{:02x}", reason_code))]
+    MqttConnectRefused { reason_code: i32 },
+    #[snafu(display("Publish refused with reason code 0x{:02x}", reason_code))]
+    MqttPublishRefused { reason_code: i32 },
+    #[snafu(display(
+        "Subscribe to {} refused with reason code 0x{:02x}",
+        topic,
+        reason_code
+    ))]
+    MqttSubscribeRefused { topic: String, reason_code: i32 },
+    #[snafu(display("Malformed MQTT property: {}", source))]
+    PropertyError { source: paho_mqtt::errors::Error },
     #[snafu(display("Malformed value {}", value))]
     MalformedValue { value: String },
     #[snafu(display("Malformed expression in value {}: {}", value, source))]
@@ -21,6 +60,50 @@ pub enum MqttVerifyError {
         value: String,
         source: evalexpr::EvalexprError,
     },
+    #[snafu(display("Malformed regex {}: {}", value, source))]
+    MalformedRegex { value: String, source: regex::Error },
+    #[snafu(display("Reading parameters from {} borked: {}", path, reason))]
+    ParameterFileError { path: String, reason: String },
+    #[snafu(display("Malformed template {}: {}", template, reason))]
+    TemplateError { template: String, reason: String },
+    #[snafu(display("Reading scenario {} borked: {}", path, source))]
+    ScenarioReadError {
+        path: String,
+        source: std::io::Error,
+    },
+    #[snafu(display("Malformed YAML scenario {}: {}", path, source))]
+    ScenarioYamlError {
+        path: String,
+        source: serde_yaml::Error,
+    },
+    #[snafu(display("Malformed TOML scenario {}: {}", path, source))]
+    ScenarioTomlError {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[snafu(display("Expected scenario {} to end in .yaml, .yml or .toml", path))]
+    UnknownScenarioFormat { path: String },
+    #[snafu(display("Client id {} of {} is already used by {}", client_id, name, first))]
+    DuplicateClientId {
+        client_id: String,
+        name: String,
+        first: String,
+    },
+    #[snafu(display(
+        "{} {} names the publisher, but several share the connection without --connection-per-publisher",
+        option,
+        template
+    ))]
+    SharedConnectionTemplate { option: String, template: String },
+    #[snafu(display("Malformed subscriber group {}: {}", group, reason))]
+    MalformedGroup { group: String, reason: String },
+    #[snafu(display("Checking for {} needs a deadline or idle timeout to end", check))]
+    UnboundedCheck { check: String },
     #[snafu(display("Verification failed: {}", reason))]
     VerificationFailure { reason: String },
+    #[snafu(display("Sink {} failed: {}", sink, source))]
+    SinkFailure {
+        sink: String,
+        source: Box<MqttVerifyError>,
+    },
 }
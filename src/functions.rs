@@ -0,0 +1,205 @@
+use evalexpr::{EvalexprError, EvalexprResult, Function, Value};
+use rand::distributions::{Alphanumeric, Uniform};
+use rand::{thread_rng, Rng};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn no_arguments(name: &str, argument: &Value) -> EvalexprResult<()> {
+    match argument {
+        Value::Empty => Ok(()),
+        _ => Err(EvalexprError::CustomMessage(format!(
+            "{}() takes no arguments",
+            name
+        ))),
+    }
+}
+
+/// A random version 4 UUID.
+fn uuid() -> String {
+    let mut bytes: [u8; 16] = thread_rng().gen();
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+    format!(
+        "{}-{}-{}-{}-{}",
+        hex[0..4].concat(),
+        hex[4..6].concat(),
+        hex[6..8].concat(),
+        hex[8..10].concat(),
+        hex[10..16].concat()
+    )
+}
+
+fn hostname() -> String {
+    fs::read_to_string("/proc/sys/kernel/hostname")
+        .or_else(|_| fs::read_to_string("/etc/hostname"))
+        .map(|name| name.trim().to_owned())
+        .or_else(|_| env::var("HOSTNAME"))
+        .or_else(|_| env::var("COMPUTERNAME"))
+        .unwrap_or_else(|_| "localhost".to_owned())
+}
+
+/// A `seq()` function with a counter of its own.
+pub fn seq() -> Function {
+    let counter = Cell::new(0);
+    Function::new(Box::new(move |argument| {
+        no_arguments("seq", argument)?;
+        counter.set(counter.get() + 1);
+        Ok(Value::Int(counter.get()))
+    }))
+}
+
+/// Functions available to expressions in every context:
+///
+/// - `uuid()`: random UUID
+/// - `now()`: seconds since the epoch, as a float
+/// - `random_int(a, b)`: random integer from `a` to `b` inclusive
+/// - `random_string(n)`: `n` random alphanumeric characters
+/// - `hostname()`: name of this host
+/// - `env("X")`: value of environment variable `X`
+/// - `seq()`: 1 on the first call, then 2 and so on; each source counts on
+///   its own, everything else shares one counter
+/// - `replace(s, from, to)`: `s` with every `from` replaced by `to`
+pub fn builtins() -> HashMap<String, Function> {
+    let mut functions = HashMap::new();
+    functions.insert(
+        "uuid".to_owned(),
+        Function::new(Box::new(|argument| {
+            no_arguments("uuid", argument)?;
+            Ok(Value::String(uuid()))
+        })),
+    );
+    functions.insert(
+        "now".to_owned(),
+        Function::new(Box::new(|argument| {
+            no_arguments("now", argument)?;
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+            Ok(Value::Float(now.as_secs_f64()))
+        })),
+    );
+    functions.insert(
+        "random_int".to_owned(),
+        Function::new(Box::new(|argument| {
+            let bounds = argument.as_fixed_len_tuple(2)?;
+            let (low, high) = (bounds[0].as_int()?, bounds[1].as_int()?);
+            if low > high {
+                return Err(EvalexprError::CustomMessage(format!(
+                    "random_int({}, {}) has an empty range",
+                    low, high
+                )));
+            }
+            Ok(Value::Int(
+                thread_rng().sample(Uniform::new_inclusive(low, high)),
+            ))
+        })),
+    );
+    functions.insert(
+        "random_string".to_owned(),
+        Function::new(Box::new(|argument| {
+            let length = argument.as_int()?;
+            Ok(Value::String(
+                thread_rng()
+                    .sample_iter(&Alphanumeric)
+                    .take(length.max(0) as usize)
+                    .collect(),
+            ))
+        })),
+    );
+    functions.insert(
+        "hostname".to_owned(),
+        Function::new(Box::new(|argument| {
+            no_arguments("hostname", argument)?;
+            Ok(Value::String(hostname()))
+        })),
+    );
+    functions.insert(
+        "env".to_owned(),
+        Function::new(Box::new(|argument| {
+            let name = argument.as_string()?;
+            env::var(&name).map(Value::String).map_err(|_| {
+                EvalexprError::CustomMessage(format!("environment variable {} is not set", name))
+            })
+        })),
+    );
+    functions.insert(
+        "replace".to_owned(),
+        Function::new(Box::new(|argument| {
+            let arguments = argument.as_fixed_len_tuple(3)?;
+            Ok(Value::String(arguments[0].as_string()?.replace(
+                &arguments[1].as_string()?,
+                &arguments[2].as_string()?,
+            )))
+        })),
+    );
+    functions.insert("seq".to_owned(), seq());
+    functions
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::context::OverlayContext;
+    use crate::errors::MqttVerifyError;
+
+    fn eval(template: &str) -> Result<String, MqttVerifyError> {
+        OverlayContext::value_for(OverlayContext::root(), template)?.value()
+    }
+
+    #[test]
+    fn uuid_is_version_4() -> Result<(), MqttVerifyError> {
+        let uuid = eval("{{uuid()}}")?;
+        assert_eq!(36, uuid.len());
+        assert_eq!(Some('4'), uuid.chars().nth(14));
+        Ok(())
+    }
+
+    #[test]
+    fn random_int_is_inclusive() -> Result<(), MqttVerifyError> {
+        assert_eq!("3", eval("{{random_int(3, 3)}}")?);
+        assert!(eval("{{random_int(3, 2)}}").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn random_string_length() -> Result<(), MqttVerifyError> {
+        assert_eq!(12, eval("{{random_string(12)}}")?.len());
+        Ok(())
+    }
+
+    #[test]
+    fn seq_counts_from_one() -> Result<(), MqttVerifyError> {
+        let value = OverlayContext::value_for(OverlayContext::root(), "{{seq()}}")?;
+        assert_eq!("1", value.value()?);
+        assert_eq!("2", value.value()?);
+        Ok(())
+    }
+
+    #[test]
+    fn seq_counts_per_source() -> Result<(), MqttVerifyError> {
+        let root = OverlayContext::root();
+        let first =
+            OverlayContext::value_for(OverlayContext::for_source(root.clone()), "{{seq()}}")?;
+        let second =
+            OverlayContext::value_for(OverlayContext::for_source(root.clone()), "{{seq()}}")?;
+        let shared = OverlayContext::value_for(OverlayContext::subcontext(root), "{{seq()}}")?;
+        assert_eq!("1", first.value()?);
+        assert_eq!("2", first.value()?);
+        assert_eq!("1", second.value()?);
+        assert_eq!("1", shared.value()?);
+        Ok(())
+    }
+
+    #[test]
+    fn replace_all() -> Result<(), MqttVerifyError> {
+        assert_eq!("b/x/b", eval(r#"{{replace("a/x/a", "a", "b")}}"#)?);
+        Ok(())
+    }
+
+    #[test]
+    fn env_requires_variable() {
+        assert!(eval(r#"{{env("PATH")}}"#).is_ok());
+        assert!(eval(r#"{{env("MQTT_VERIFY_SURELY_UNSET")}}"#).is_err());
+    }
+}
@@ -1,120 +1,787 @@
+use crate::analyzers::Analyzer;
 use crate::source::Source;
-use futures::{future, stream, stream::StreamExt, stream::TryStreamExt};
+use futures::channel::oneshot;
+use futures::lock::Mutex;
+use futures::{future, future::Either, future::FutureExt, stream, stream::StreamExt};
+use futures_timer::Delay;
+use log::info;
 use paho_mqtt as mqtt;
+use std::cell::{Cell, RefCell};
 use std::cmp;
-use std::iter::FromIterator;
 use std::pin::Pin;
 use std::time::{Duration, Instant};
 
 pub mod analyzers;
+pub mod backoff;
 pub mod context;
+pub mod credentials;
+pub mod definition;
 pub mod errors;
+pub mod functions;
+pub mod parameters;
+pub mod report;
 pub mod scenario;
 pub mod source;
+pub mod tls;
+pub mod v5;
+pub mod will;
 
 pub fn client(uri: &str) -> mqtt::AsyncClient {
+    create_client(uri, "", mqtt::MQTT_VERSION_DEFAULT)
+}
+
+/// What a client was created with, which Paho does not tell.
+struct ClientInfo {
+    uri: String,
+    client_id: String,
+}
+
+/// Client speaking `mqtt_version`, one of the `mqtt::MQTT_VERSION_*`
+/// constants. An empty client id lets the broker assign one.
+pub fn create_client(uri: &str, client_id: &str, mqtt_version: u32) -> mqtt::AsyncClient {
     let mqtt_opts = mqtt::CreateOptionsBuilder::new()
         .server_uri(uri)
+        .client_id(client_id)
         .persistence(mqtt::create_options::PersistenceType::None)
+        .mqtt_version(mqtt_version)
+        .user_data(Box::new(ClientInfo {
+            uri: uri.to_owned(),
+            client_id: client_id.to_owned(),
+        }))
         .finalize();
     mqtt::AsyncClient::new(mqtt_opts).unwrap()
 }
 
+fn client_info(client: &mqtt::AsyncClient) -> Option<&ClientInfo> {
+    client.user_data()?.downcast_ref()
+}
+
+/// The URI given to `create_client` for `client`, empty for clients created
+/// otherwise.
+pub fn server_uri(client: &mqtt::AsyncClient) -> &str {
+    client_info(client).map_or("", |info| &info.uri)
+}
+
+/// The client id given to `create_client` for `client`, empty for clients
+/// created otherwise.
+pub fn client_id(client: &mqtt::AsyncClient) -> &str {
+    client_info(client).map_or("", |info| &info.client_id)
+}
+
 pub type MessageStream =
     Pin<Box<dyn stream::Stream<Item = Result<mqtt::Message, errors::MqttVerifyError>>>>;
 
-fn publisher_messages(publisher: scenario::Publisher) -> MessageStream {
+fn publisher_messages(sources: Vec<source::VerifiableSource>) -> MessageStream {
     Box::pin(stream::select_all(
-        publisher
-            .sources
-            .into_iter()
-            .map(|generator| generator.messages()),
+        sources.into_iter().map(|generator| generator.messages()),
     ))
 }
 
+/// Connect, retrying after the delays of `backoff` until `timeout` has passed.
 async fn connect(
     client: &mqtt::AsyncClient,
     timeout: &Duration,
+    tls: Option<&tls::TlsOptions>,
+    credentials: Option<&credentials::Credentials>,
+    will: Option<&will::Will>,
+    clean_session: bool,
+    backoff: &backoff::Backoff,
 ) -> Result<(), errors::MqttVerifyError> {
     let ref max_interval = Duration::from_secs(1);
     let interval = cmp::min(timeout, max_interval);
-    let conn_opts = mqtt::ConnectOptionsBuilder::new()
-        .clean_session(true)
-        .connect_timeout(*interval)
-        .finalize();
+    let mut builder = mqtt::ConnectOptionsBuilder::new();
+    builder.connect_timeout(*interval);
+    if client.mqtt_version() == mqtt::MQTT_VERSION_5 {
+        builder
+            .mqtt_version(mqtt::MQTT_VERSION_5)
+            .clean_start(clean_session);
+        if !clean_session {
+            builder.properties(v5::session_properties()?);
+        }
+    } else {
+        builder.clean_session(clean_session);
+    }
+    if let Some(tls) = tls {
+        builder.ssl_options(tls.ssl_options()?);
+    }
+    if let Some(credentials) = credentials {
+        builder.user_name(credentials.username.as_str());
+        if let Some(ref password) = credentials.password {
+            builder.password(password.as_str());
+        }
+    }
+    if let Some(will) = will {
+        builder.will_message(will.message());
+    }
+    let conn_opts = builder.finalize();
 
     let deadline = Instant::now() + *timeout;
+    let mut attempt = 0;
     loop {
         match client.connect(conn_opts.clone()).await {
             Ok(_) => return Ok(()),
-            Err(_) if Instant::now() < deadline => continue,
-            Err(err) => return Err(errors::MqttVerifyError::MqttConnectError { source: err }),
+            Err(err) => {
+                if let Some(reason_code) = v5::reason_code(&err) {
+                    return Err(errors::MqttVerifyError::MqttConnectRefused { reason_code });
+                }
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if remaining > Duration::from_secs(0) {
+                    Delay::new(cmp::min(backoff.delay(attempt), remaining)).await;
+                    attempt += 1;
+                    continue;
+                }
+                let uri = server_uri(client).to_owned();
+                return Err(
+                    if tls.is_some() && tls::failed_in_handshake(&uri, &err, *interval).await {
+                        errors::MqttVerifyError::TlsHandshakeError { uri, source: err }
+                    } else {
+                        errors::MqttVerifyError::MqttConnectError { source: err }
+                    },
+                );
+            }
         }
     }
 }
 
-pub async fn run_publisher(publisher: scenario::Publisher) -> Result<(), errors::MqttVerifyError> {
+fn publish_error(err: mqtt::Error) -> errors::MqttVerifyError {
+    match v5::reason_code(&err) {
+        Some(reason_code) => errors::MqttVerifyError::MqttPublishRefused { reason_code },
+        None => errors::MqttVerifyError::MqttPublishError { source: err },
+    }
+}
+
+/// Subscribe to all topics, failing on the first one the broker refuses.
+async fn subscribe(
+    client: &mqtt::AsyncClient,
+    topics: &[scenario::Subscription],
+) -> Result<(), errors::MqttVerifyError> {
+    let (topics, qos): (Vec<String>, Vec<i32>) = topics
+        .iter()
+        .map(|subscription| (subscription.topic.clone(), subscription.qos))
+        .unzip();
+    let subscribe_error = |err| match v5::reason_code(&err) {
+        Some(reason_code) => errors::MqttVerifyError::MqttSubscribeRefused {
+            topic: topics.join(", "),
+            reason_code,
+        },
+        None => errors::MqttVerifyError::MqttSubscribeError { source: err },
+    };
+    let response = client
+        .subscribe_many(&topics, &qos)
+        .await
+        .map_err(subscribe_error)?;
+    let granted = response.subscribe_many_response().unwrap_or_default();
+    for (topic, reason_code) in topics.iter().zip(granted) {
+        if reason_code >= 0x80 {
+            return Err(errors::MqttVerifyError::MqttSubscribeRefused {
+                topic: topic.clone(),
+                reason_code,
+            });
+        }
+    }
+    Ok(())
+}
+
+fn until(deadline: Instant) -> Delay {
+    Delay::new(deadline.saturating_duration_since(Instant::now()))
+}
+
+/// How long to wait for the next message and what to blame if none arrives.
+fn next_timeout(
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+) -> Option<(Duration, String)> {
+    let deadline = deadline.map(|deadline| {
+        (
+            deadline.saturating_duration_since(Instant::now()),
+            "scenario deadline exceeded".to_owned(),
+        )
+    });
+    let idle = idle_timeout.map(|timeout| {
+        (
+            timeout,
+            format!("no message for {:.3}s", timeout.as_secs_f64()),
+        )
+    });
+    match (deadline, idle) {
+        (Some(deadline), Some(idle)) if idle.0 < deadline.0 => Some(idle),
+        (deadline, idle) => deadline.or(idle),
+    }
+}
+
+pub async fn run_publisher(
+    publisher: scenario::Publisher,
+) -> Result<report::PublisherReport, errors::MqttVerifyError> {
+    run_publisher_until(publisher, None).await
+}
+
+/// Run the publisher, abandoning messages not yet published at `deadline`.
+/// When the connection is lost, the first publish to fail reconnects while
+/// the others wait, and each of them is tried once more.
+pub async fn run_publisher_until(
+    mut publisher: scenario::Publisher,
+    deadline: Option<Instant>,
+) -> Result<report::PublisherReport, errors::MqttVerifyError> {
+    let started = Instant::now();
+    let name = publisher.name.clone();
     let client = publisher.client.clone();
-    connect(&client, &publisher.initial_timeout).await?;
-    publisher_messages(publisher)
-        .try_for_each_concurrent(None, |message| {
-            let client2 = client.clone();
-            async move {
-                client2
-                    .publish(message)
-                    .await
-                    .map_err(|err| errors::MqttVerifyError::MqttPublishError { source: err })
+    let mut relay = publisher.relay.take();
+    if let Some(ref mut relay) = relay {
+        relay.start();
+    }
+    let sources = std::mem::take(&mut publisher.sources);
+    let reconnect = publisher.reconnect;
+    let connect_publisher = || {
+        connect(
+            &client,
+            &publisher.initial_timeout,
+            publisher.tls.as_ref(),
+            publisher.credentials.as_ref(),
+            publisher.will.as_ref(),
+            true,
+            &publisher.backoff,
+        )
+    };
+    connect_publisher().await?;
+    let sent = Cell::new(0);
+    let reconnects = Cell::new(0);
+    let reconnecting = Mutex::new(());
+    let failures = RefCell::new(Vec::new());
+    let publishing = publisher_messages(sources).for_each_concurrent(None, |message| {
+        let client2 = client.clone();
+        let (sent, failures, name) = (&sent, &failures, &name);
+        let (reconnects, reconnecting, connect_publisher) =
+            (&reconnects, &reconnecting, &connect_publisher);
+        async move {
+            let result = match message {
+                Ok(message) => match client2.publish(message.clone()).await {
+                    Err(_) if reconnect && !client2.is_connected() => {
+                        let _reconnecting = reconnecting.lock().await;
+                        let reconnected = if client2.is_connected() {
+                            Ok(())
+                        } else {
+                            info!("{} lost its connection", name);
+                            connect_publisher()
+                                .await
+                                .map(|_| reconnects.set(reconnects.get() + 1))
+                        };
+                        match reconnected {
+                            Ok(_) => client2.publish(message).await.map_err(publish_error),
+                            Err(err) => Err(err),
+                        }
+                    }
+                    result => result.map_err(publish_error),
+                },
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(_) => sent.set(sent.get() + 1),
+                Err(err) => failures.borrow_mut().push(err.to_string()),
             }
-        })
-        .await?;
-    client
-        .disconnect_after(Duration::from_secs(3))
-        .await
-        .map(|_| ()) // TODO: What is this ServerResponse thing anyway?
-        .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })
+        }
+    });
+    match deadline {
+        Some(deadline) => {
+            if let Either::Right(_) = future::select(Box::pin(publishing), until(deadline)).await {
+                failures
+                    .borrow_mut()
+                    .push("scenario deadline exceeded while publishing".to_owned());
+            }
+        }
+        None => publishing.await,
+    }
+    match relay {
+        Some(relay) => {
+            relay.sever();
+            info!("{} severed its connection", name);
+        }
+        None => {
+            if let Err(err) = client
+                .disconnect_after(Duration::from_secs(3))
+                .await
+                .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })
+            {
+                failures.borrow_mut().push(err.to_string());
+            }
+        }
+    }
+    Ok(report::PublisherReport {
+        name,
+        sent: sent.get(),
+        errors: failures.into_inner(),
+        reconnects: reconnects.get(),
+        elapsed: started.elapsed(),
+    })
 }
 
 pub async fn run_subscriber(
-    mut subscriber: scenario::Subscriber,
+    subscriber: scenario::Subscriber,
+) -> Result<report::SubscriberReport, errors::MqttVerifyError> {
+    run_subscriber_until(subscriber, None).await
+}
+
+/// Why a subscriber stopped receiving.
+enum Stop<L> {
+    Analyzed,
+    Paused,
+    /// A connection was lost, as told by the stream
+    Lost(L),
+    Expired(String),
+}
+
+/// Feed messages to `analyze` until it is done or failed, or no message
+/// arrives in time. Stop without blaming anyone at `pause` or when the stream
+/// tells of a lost connection.
+async fn receive<T, L>(
+    messages: &mut (dyn stream::Stream<Item = Result<T, L>> + Unpin),
+    mut analyze: impl FnMut(T) -> Result<analyzers::State, errors::MqttVerifyError>,
+    received: &mut usize,
+    deadline: Option<Instant>,
+    idle_timeout: Option<Duration>,
+    pause: Option<Instant>,
+) -> Stop<L> {
+    loop {
+        let mut timeout = next_timeout(deadline, idle_timeout).map(|(t, cause)| (t, Some(cause)));
+        if let Some(pause) = pause {
+            let until_pause = pause.saturating_duration_since(Instant::now());
+            match timeout {
+                Some((timeout, _)) if timeout <= until_pause => (),
+                _ => timeout = Some((until_pause, None)),
+            }
+        }
+        let next = match timeout {
+            Some((timeout, cause)) => {
+                match future::select(messages.next(), Delay::new(timeout)).await {
+                    Either::Left((message, _)) => message,
+                    Either::Right(_) => {
+                        return match cause {
+                            Some(cause) => Stop::Expired(cause),
+                            None => Stop::Paused,
+                        }
+                    }
+                }
+            }
+            None => messages.next().await,
+        };
+        let message = match next {
+            Some(Ok(message)) => message,
+            Some(Err(lost)) => return Stop::Lost(lost),
+            None => return Stop::Expired("connection closed".to_owned()),
+        };
+        *received += 1;
+        if !matches!(analyze(message), Ok(analyzers::State::Continue)) {
+            return Stop::Analyzed;
+        }
+    }
+}
+
+/// Connect again after losing the connection. Subscribe again unless the
+/// broker kept the subscriptions in a persistent session.
+async fn reconnect_subscriber(
+    subscriber: &scenario::Subscriber,
+    client: &mqtt::AsyncClient,
 ) -> Result<(), errors::MqttVerifyError> {
-    let mut analyzer = subscriber.sinks.remove(0);
+    info!("{} lost its connection", subscriber.name);
+    let clean_session = subscriber.session.is_none();
+    connect(
+        client,
+        &subscriber.initial_timeout,
+        subscriber.tls.as_ref(),
+        subscriber.credentials.as_ref(),
+        None,
+        clean_session,
+        &subscriber.backoff,
+    )
+    .await?;
+    if clean_session {
+        subscribe(client, &subscriber.topics).await?;
+    }
+    Ok(())
+}
+
+/// Run the subscriber, giving up on sinks still pending at `deadline` or when
+/// its idle timeout expires. With a persistent session, the subscriber goes
+/// offline for a while and resumes the session without subscribing again.
+/// A lost connection ends the run unless the subscriber reconnects.
+pub async fn run_subscriber_until(
+    mut subscriber: scenario::Subscriber,
+    deadline: Option<Instant>,
+) -> Result<report::SubscriberReport, errors::MqttVerifyError> {
+    let started = Instant::now();
+    let mut analyzer = analyzers::FanOut::new(std::mem::take(&mut subscriber.sinks));
     let mut client = subscriber.client.clone();
-    connect(&client, &subscriber.initial_timeout).await?;
-    client
-        .subscribe_many(&subscriber.topics, &vec![0; subscriber.topics.len()])
-        .await
-        .map_err(|err| errors::MqttVerifyError::MqttSubscribeError { source: err })?;
-    let mut messages = client
-        .get_stream(100)
-        .take_while(|message| future::ready(message.is_some()))
-        .map(|message| message.unwrap());
-    while let Some(message) = messages.next().await {
-        match analyzer.analyze(message)? {
-            analyzers::State::Continue => (),
-            analyzers::State::Done => break,
-        };
+    let mut messages = client.get_stream(100).map(|message| message.ok_or(()));
+    let connect_subscriber = |clean_session| {
+        connect(
+            &client,
+            &subscriber.initial_timeout,
+            subscriber.tls.as_ref(),
+            subscriber.credentials.as_ref(),
+            None,
+            clean_session,
+            &subscriber.backoff,
+        )
+    };
+    if subscriber.session.is_some() {
+        // Start from an empty session rather than one left by an earlier run
+        connect_subscriber(true).await?;
+        client
+            .disconnect(None)
+            .await
+            .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })?;
     }
-    client
-        .disconnect_after(Duration::from_secs(3))
-        .await
-        .map(|_| ()) // TODO: What is this ServerResponse thing anyway?
-        .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })
-}
-
-pub fn run_scenario(
-    mut scenario: scenario::Scenario,
-) -> Pin<Box<dyn stream::Stream<Item = Result<(), errors::MqttVerifyError>>>> {
-    type FutureResult = Pin<Box<dyn future::Future<Output = Result<(), errors::MqttVerifyError>>>>;
-    let results = scenario
-        .publishers
-        .drain(..)
-        .map(|publisher| Box::pin(Box::pin(run_publisher(publisher))) as FutureResult)
-        .chain(
-            scenario
-                .subscribers
-                .drain(..)
-                .map(|subscriber| Box::pin(run_subscriber(subscriber)) as FutureResult),
+    connect_subscriber(subscriber.session.is_none()).await?;
+    subscribe(&client, &subscriber.topics).await?;
+    let mut received = 0;
+    let mut reconnects = 0;
+    let mut error = None;
+    let mut pause = subscriber
+        .session
+        .as_ref()
+        .map(|session| Instant::now() + session.offline_after);
+    let stop = loop {
+        let stop = receive(
+            &mut messages,
+            |message| analyzer.analyze(message),
+            &mut received,
+            deadline,
+            subscriber.idle_timeout,
+            pause,
+        )
+        .await;
+        match stop {
+            Stop::Paused => {
+                pause = None;
+                let offline_for = subscriber.session.as_ref().unwrap().offline_for;
+                client
+                    .disconnect(None)
+                    .await
+                    .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })?;
+                info!(
+                    "{} offline for {:.3}s after {} messages",
+                    subscriber.name,
+                    offline_for.as_secs_f64(),
+                    received
+                );
+                Delay::new(offline_for).await;
+                connect_subscriber(false).await?;
+            }
+            Stop::Lost(_) if subscriber.reconnect => {
+                match reconnect_subscriber(&subscriber, &client).await {
+                    Ok(_) => reconnects += 1,
+                    Err(err) => {
+                        error = Some(err.to_string());
+                        break Stop::Expired("connection lost".to_owned());
+                    }
+                }
+            }
+            Stop::Lost(_) => break Stop::Expired("connection closed".to_owned()),
+            stop => break stop,
+        }
+    };
+    if let Stop::Expired(cause) = stop {
+        analyzer.expire(&cause);
+    }
+    info!("{} received {} messages", subscriber.name, received);
+    if error.is_none() {
+        error = client
+            .disconnect_after(Duration::from_secs(3))
+            .await
+            .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })
+            .err()
+            .map(|err| err.to_string());
+    }
+    Ok(report::SubscriberReport {
+        name: subscriber.name,
+        received,
+        error,
+        reconnects,
+        sinks: analyzer.reports(),
+        elapsed: started.elapsed(),
+    })
+}
+
+/// Run the members of a group together, feeding what each of them receives
+/// to its own sinks and all of it to the group's. Reports on every member
+/// and then on the group as a whole.
+pub async fn run_group_until(
+    group: scenario::SubscriberGroup,
+    deadline: Option<Instant>,
+) -> Vec<report::SubscriberReport> {
+    let started = Instant::now();
+    let names = group
+        .members
+        .iter()
+        .map(|member| member.name.clone())
+        .collect();
+    let mut analyzer = analyzers::GroupAnalyzer::new(names, group.sinks, group.min_share);
+    let mut members = Vec::new();
+    let mut streams = Vec::new();
+    for (index, member) in group.members.into_iter().enumerate() {
+        let mut client = member.client.clone();
+        streams.push(
+            client
+                .get_stream(100)
+                .map(move |message| message.map(|message| (index, message)).ok_or(index)),
         );
+        members.push((member, client, None, 0));
+    }
+    let setups = members.iter().map(|(member, client, _, _)| async move {
+        connect(
+            client,
+            &member.initial_timeout,
+            member.tls.as_ref(),
+            member.credentials.as_ref(),
+            None,
+            true,
+            &member.backoff,
+        )
+        .await?;
+        subscribe(client, &member.topics).await
+    });
+    let setups = future::join_all(setups).await;
+    for ((_, _, error, _), setup) in members.iter_mut().zip(setups) {
+        *error = setup.err().map(|err| err.to_string());
+    }
+    let mut fan_outs: Vec<analyzers::FanOut> = members
+        .iter_mut()
+        .map(|(member, _, _, _)| analyzers::FanOut::new(member.sinks.drain(..).collect()))
+        .collect();
+    let mut messages = stream::select_all(streams);
+    let mut received = 0;
+    let stop = loop {
+        let stop = receive(
+            &mut messages,
+            |(member, message)| {
+                // Members carry on after their own sinks fail, which their
+                // fan-outs record for the sink verdicts in the report
+                let _ = fan_outs[member].analyze(message.clone());
+                analyzer.analyze(member, message)
+            },
+            &mut received,
+            deadline,
+            group.idle_timeout,
+            None,
+        )
+        .await;
+        match stop {
+            // The others carry on regardless
+            Stop::Lost(index) => {
+                let (member, client, error, reconnects) = &mut members[index];
+                if member.reconnect && error.is_none() {
+                    match reconnect_subscriber(member, client).await {
+                        Ok(_) => *reconnects += 1,
+                        Err(err) => *error = Some(err.to_string()),
+                    }
+                }
+            }
+            stop => break stop,
+        }
+    };
+    let cause = match stop {
+        Stop::Expired(cause) => {
+            analyzer.expire(&cause);
+            cause
+        }
+        _ => format!("group {} done", group.name),
+    };
+    info!("{} received {} messages", group.name, received);
+    let mut reports = Vec::new();
+    let mut total_reconnects = 0;
+    for (index, ((member, client, mut error, reconnects), mut fan_out)) in
+        members.into_iter().zip(fan_outs).enumerate()
+    {
+        fan_out.expire(&cause);
+        if error.is_none() {
+            error = client
+                .disconnect_after(Duration::from_secs(3))
+                .await
+                .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })
+                .err()
+                .map(|err| err.to_string());
+        }
+        reports.push(report::SubscriberReport {
+            name: member.name,
+            received: analyzer.received(index),
+            error,
+            reconnects,
+            sinks: fan_out.reports(),
+            elapsed: started.elapsed(),
+        });
+        total_reconnects += reconnects;
+    }
+    reports.push(report::SubscriberReport {
+        name: group.name,
+        received,
+        error: None,
+        reconnects: total_reconnects,
+        sinks: analyzer.reports(),
+        elapsed: started.elapsed(),
+    });
+    reports
+}
+
+async fn publisher_report(
+    publisher: scenario::Publisher,
+    deadline: Option<Instant>,
+) -> report::PublisherReport {
+    let started = Instant::now();
+    let name = publisher.name.clone();
+    run_publisher_until(publisher, deadline)
+        .await
+        .unwrap_or_else(|err| report::PublisherReport {
+            name,
+            sent: 0,
+            errors: vec![err.to_string()],
+            reconnects: 0,
+            elapsed: started.elapsed(),
+        })
+}
 
-    Box::pin(stream::FuturesUnordered::from_iter(results).fuse())
+async fn subscriber_report(
+    subscriber: scenario::Subscriber,
+    deadline: Option<Instant>,
+) -> report::SubscriberReport {
+    let started = Instant::now();
+    let name = subscriber.name.clone();
+    run_subscriber_until(subscriber, deadline)
+        .await
+        .unwrap_or_else(|err| report::SubscriberReport {
+            name,
+            received: 0,
+            error: Some(err.to_string()),
+            reconnects: 0,
+            sinks: Vec::new(),
+            elapsed: started.elapsed(),
+        })
+}
+
+/// What it takes to clear the retained messages a publisher left behind.
+struct RetainedCleanup {
+    client: mqtt::AsyncClient,
+    initial_timeout: Duration,
+    tls: Option<tls::TlsOptions>,
+    credentials: Option<credentials::Credentials>,
+    backoff: backoff::Backoff,
+    topics: Vec<String>,
+}
+
+impl RetainedCleanup {
+    fn for_publisher(
+        publisher: &scenario::Publisher,
+    ) -> Result<Option<Self>, errors::MqttVerifyError> {
+        let topics = publisher.retained_topics()?;
+        Ok(if topics.is_empty() {
+            None
+        } else {
+            // A severed relay no longer reaches the broker
+            let uri = match publisher.relay {
+                Some(ref relay) => relay.broker_uri(),
+                None => server_uri(&publisher.client),
+            };
+            // Connecting with the publisher's own id would take over its session
+            let client_id = match client_id(&publisher.client) {
+                "" => String::new(),
+                client_id => format!("{}-cleanup", client_id),
+            };
+            Some(Self {
+                client: create_client(uri, &client_id, publisher.client.mqtt_version()),
+                initial_timeout: publisher.initial_timeout,
+                tls: publisher.tls.clone(),
+                credentials: publisher.credentials.clone(),
+                backoff: publisher.backoff.clone(),
+                topics,
+            })
+        })
+    }
+
+    /// Publish an empty retained message to each topic in a clean session,
+    /// which makes the broker drop what it retained there.
+    async fn run(self) -> Result<(), errors::MqttVerifyError> {
+        connect(
+            &self.client,
+            &self.initial_timeout,
+            self.tls.as_ref(),
+            self.credentials.as_ref(),
+            None,
+            true,
+            &self.backoff,
+        )
+        .await?;
+        for topic in self.topics {
+            self.client
+                .publish(mqtt::Message::new_retained(topic, Vec::new(), 1))
+                .await
+                .map_err(publish_error)?;
+        }
+        self.client
+            .disconnect_after(Duration::from_secs(3))
+            .await
+            .map_err(|err| errors::MqttVerifyError::MqttDisconnectError { source: err })?;
+        Ok(())
+    }
+}
+
+/// Run all publishers, subscribers and groups concurrently and report on the
+/// outcome, with groups reported after the subscribers. Subscribers marked to
+/// start after publishing wait for all publishers to finish. Retained messages
+/// are cleared afterwards unless the scenario keeps them.
+pub async fn run_scenario(scenario: scenario::Scenario) -> report::Report {
+    let deadline = scenario.deadline.map(|deadline| Instant::now() + deadline);
+    let cleanups: Vec<Result<Option<RetainedCleanup>, errors::MqttVerifyError>> =
+        if scenario.keep_retained {
+            Vec::new()
+        } else {
+            scenario
+                .publishers
+                .iter()
+                .map(RetainedCleanup::for_publisher)
+                .collect()
+        };
+    let (published, published_signal) = oneshot::channel::<()>();
+    let published_signal = published_signal.shared();
+    let publishers = future::join_all(
+        scenario
+            .publishers
+            .into_iter()
+            .map(|publisher| publisher_report(publisher, deadline)),
+    )
+    .map(|reports| {
+        let _ = published.send(());
+        reports
+    });
+    let subscribers = future::join_all(scenario.subscribers.into_iter().map(|subscriber| {
+        let published_signal = published_signal.clone();
+        async move {
+            if subscriber.after_publishing {
+                let _ = published_signal.await;
+            }
+            subscriber_report(subscriber, deadline).await
+        }
+    }));
+    let groups = future::join_all(
+        scenario
+            .groups
+            .into_iter()
+            .map(|group| run_group_until(group, deadline)),
+    );
+    let (mut publishers, (mut subscribers, groups)) =
+        future::join(publishers, future::join(subscribers, groups)).await;
+    subscribers.extend(groups.into_iter().flatten());
+    for (report, cleanup) in publishers.iter_mut().zip(cleanups) {
+        let cleared = match cleanup {
+            Ok(Some(cleanup)) => cleanup.run().await,
+            Ok(None) => Ok(()),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = cleared {
+            report
+                .errors
+                .push(format!("Clearing retained messages borked: {}", err));
+        }
+    }
+    report::Report {
+        publishers,
+        subscribers,
+    }
 }
@@ -1,23 +1,19 @@
 use async_std::task;
 use evalexpr::Value;
-use futures::stream::StreamExt;
-use mqtt_verify::{analyzers, context, errors, scenario, source};
+use mqtt_verify::{
+    analyzers, backoff, context, credentials, definition, errors, parameters, report, scenario,
+    source, tls, v5, will,
+};
+use paho_mqtt as mqtt;
+use regex::Regex;
+use std::cmp;
+use std::path::PathBuf;
+use std::process;
 use std::rc::Rc;
 use std::str::FromStr;
 use std::time::Duration;
 use structopt::StructOpt;
 
-fn split_on_equal(input: &str) -> Result<(String, String), errors::MqttVerifyError> {
-    let pair: Vec<&str> = input.splitn(2, '=').collect();
-    if pair.len() == 2 {
-        Ok((pair[0].to_owned(), pair[1].to_owned()))
-    } else {
-        Err(errors::MqttVerifyError::MalformedParameter {
-            input: input.to_owned(),
-        })
-    }
-}
-
 fn duration_from_str(input: &str) -> Result<Duration, errors::MqttVerifyError> {
     let secs = f32::from_str(input).map_err(|_| errors::MqttVerifyError::MalformedValue {
         value: input.to_owned(),
@@ -28,94 +24,706 @@ fn duration_from_str(input: &str) -> Result<Duration, errors::MqttVerifyError> {
 #[derive(StructOpt, Debug)]
 #[structopt()]
 pub struct Opt {
+    /// YAML or TOML scenario file to run instead of the scenario described by options
+    #[structopt(long = "scenario", env = "SCENARIO", parse(from_os_str))]
+    scenario: Option<PathBuf>,
     /// URI to publish messages to
-    #[structopt(long = "publish-uri", env = "PUBLISH_URI")]
-    publish_uri: String,
+    #[structopt(
+        long = "publish-uri",
+        env = "PUBLISH_URI",
+        required_unless = "scenario"
+    )]
+    publish_uri: Option<String>,
     /// Number of parallel publishers
     #[structopt(long = "publishers", env = "PUBLISHERS", default_value = "1")]
     publishers: u64,
+    /// Give each publisher its own connection instead of sharing one
+    #[structopt(long = "connection-per-publisher")]
+    connection_per_publisher: bool,
+    /// Client id of each publisher connection, may use `publisher` for the
+    /// name of the publisher it serves unless several share the connection
+    #[structopt(long = "publish-client-id", env = "PUBLISH_CLIENT_ID")]
+    publish_client_id: Option<String>,
     /// Frequency (Hz) messages messages per session
     #[structopt(long = "frequency", env = "FREQUENCY", default_value = "1.0")]
     frequency: f32,
     /// Session length in seconds
     #[structopt(long = "length", env = "LENGTH", default_value = "10.0")]
     length: f32,
-    /// Topic to publish to
+    /// Topic to publish to; may use id, seq, total and timestamp of the message
     #[structopt(long = "topic", env = "TOPIC", default_value = "1")]
     topic: String,
+    /// Topic filter to subscribe to, defaults to the topic published to
+    #[structopt(long = "subscribe-topic", env = "SUBSCRIBE_TOPIC")]
+    subscribe_topic: Option<String>,
+    /// Template for what follows the verification marker in each payload; like
+    /// the topic, may use id, seq, total and timestamp
+    #[structopt(long = "payload", env = "PAYLOAD")]
+    payload: Option<String>,
+    /// QoS to publish with; at QoS 0 lost messages are tolerated, at QoS 1 repeated ones
+    #[structopt(long = "qos", env = "QOS", default_value = "0", possible_values = &["0", "1", "2"])]
+    qos: i32,
+    /// Publish retained messages
+    #[structopt(long = "retain")]
+    retain: bool,
+    /// Leave retained messages on the broker instead of clearing them afterwards
+    #[structopt(long = "keep-retained")]
+    keep_retained: bool,
+    /// Subscribe only once publishing is done and verify just the last
+    /// retained message on each topic
+    #[structopt(long = "subscribe-after-publishing")]
+    subscribe_after_publishing: bool,
+    /// Topic of the publisher's will, which is then verified to be published
+    /// only if the publisher severs its connection; verifying that it is not
+    /// needs --deadline or --idle-timeout
+    #[structopt(long = "will-topic", env = "WILL_TOPIC")]
+    will_topic: Option<String>,
+    /// Payload of the publisher's will
+    #[structopt(long = "will-payload", env = "WILL_PAYLOAD", default_value = "")]
+    will_payload: String,
+    /// QoS of the publisher's will
+    #[structopt(long = "will-qos", env = "WILL_QOS", default_value = "0", possible_values = &["0", "1", "2"])]
+    will_qos: i32,
+    /// Drop the publisher's connection without disconnecting when done; not
+    /// supported with TLS
+    #[structopt(long = "sever")]
+    sever: bool,
+    /// Client id of each subscriber, may use `subscriber` for its name; a
+    /// persistent session defaults to mqtt-verify-{{subscriber}}
+    #[structopt(long = "subscribe-client-id", env = "SUBSCRIBE_CLIENT_ID")]
+    subscribe_client_id: Option<String>,
+    /// Subscribe in a persistent session and disconnect this many seconds
+    /// later, expecting messages published while offline to be queued
+    #[structopt(long = "offline-after", env = "OFFLINE_AFTER", parse(try_from_str = duration_from_str))]
+    offline_after: Option<Duration>,
+    /// Seconds to stay disconnected from a persistent session
+    #[structopt(long = "offline-for", env = "OFFLINE_FOR", default_value = "1", parse(try_from_str = duration_from_str))]
+    offline_for: Duration,
+    /// MQTT protocol version to connect with, defaults to whatever the broker
+    /// accepts
+    #[structopt(long = "mqtt-version", env = "MQTT_VERSION", possible_values = &["3.1", "3.1.1", "5"], parse(try_from_str = v5::parse_version))]
+    mqtt_version: Option<u32>,
+    /// MQTT v5 user property of every published message, as key=value where
+    /// the value may use the message variables; repeat for more
+    #[structopt(long = "user-property", parse(try_from_str = v5::parse_user_property))]
+    user_properties: Vec<(String, String)>,
+    /// Seconds the broker keeps each published message for subscribers (MQTT v5)
+    #[structopt(long = "message-expiry", env = "MESSAGE_EXPIRY")]
+    message_expiry: Option<u32>,
+    /// Content type of published messages (MQTT v5)
+    #[structopt(long = "content-type", env = "CONTENT_TYPE")]
+    content_type: Option<String>,
+    /// Response topic of published messages, may use the message variables (MQTT v5)
+    #[structopt(long = "response-topic", env = "RESPONSE_TOPIC")]
+    response_topic: Option<String>,
+    /// Correlation data of published messages, may use the message variables (MQTT v5)
+    #[structopt(long = "correlation-data", env = "CORRELATION_DATA")]
+    correlation_data: Option<String>,
+    /// Subscribe as a member of this shared subscription group (MQTT v5);
+    /// several subscribers are then verified together
+    #[structopt(long = "subscribe-share", env = "SUBSCRIBE_SHARE")]
+    subscribe_share: Option<String>,
+    /// Least fraction of an even share each subscriber in a shared
+    /// subscription group must receive
+    #[structopt(long = "min-share", env = "MIN_SHARE", default_value = "0.5")]
+    min_share: f64,
+    /// Number of subscribers, each verifying everything published unless
+    /// they share a subscription
+    #[structopt(long = "subscribers", env = "SUBSCRIBERS", default_value = "1")]
+    subscribers: u64,
+    /// QoS to subscribe with, defaults to the publish QoS
+    #[structopt(long = "subscribe-qos", env = "SUBSCRIBE_QOS", possible_values = &["0", "1", "2"])]
+    subscribe_qos: Option<i32>,
     /// URI to verify messages from
-    #[structopt(long = "subscribe-uri", env = "PUBLISH_URI")]
-    subscribe_uri: String,
+    #[structopt(
+        long = "subscribe-uri",
+        env = "PUBLISH_URI",
+        required_unless = "scenario"
+    )]
+    subscribe_uri: Option<String>,
     /// Timeout waiting to connect to broker, both when publishing and subscribing
     #[structopt(long = "initial-timeout", env = "INITIAL_TIMEOUT", default_value = "1.0", parse(try_from_str = duration_from_str))]
     initial_timeout: Duration,
-    /// Parameter for expansion
-    #[structopt(long = "parameter", parse(try_from_str = split_on_equal))]
-    parameters: Vec<(String, String)>,
+    /// Seconds to wait before trying to connect again, doubling with each
+    /// attempt
+    #[structopt(long = "backoff-initial", env = "BACKOFF_INITIAL", default_value = "0.1", parse(try_from_str = duration_from_str))]
+    backoff_initial: Duration,
+    /// Longest wait between attempts to connect
+    #[structopt(long = "backoff-max", env = "BACKOFF_MAX", default_value = "2", parse(try_from_str = duration_from_str))]
+    backoff_max: Duration,
+    /// Fraction of each wait between attempts to connect to randomly cut,
+    /// between 0 and 1
+    #[structopt(long = "backoff-jitter", env = "BACKOFF_JITTER", default_value = "0.5")]
+    backoff_jitter: f64,
+    /// Connect and subscribe again when the connection to the broker is lost
+    #[structopt(long = "reconnect")]
+    reconnect: bool,
+    /// PEM bundle of CAs trusted to sign the broker certificate
+    #[structopt(long = "ca-file", env = "CA_FILE", parse(from_os_str))]
+    ca_file: Option<PathBuf>,
+    /// PEM client certificate for mutual TLS
+    #[structopt(long = "cert-file", env = "CERT_FILE", parse(from_os_str))]
+    cert_file: Option<PathBuf>,
+    /// PEM private key for the client certificate
+    #[structopt(long = "key-file", env = "KEY_FILE", parse(from_os_str))]
+    key_file: Option<PathBuf>,
+    /// Client certificate for the subscriber, defaults to the one given by --cert-file
+    #[structopt(
+        long = "subscribe-cert-file",
+        env = "SUBSCRIBE_CERT_FILE",
+        parse(from_os_str)
+    )]
+    subscribe_cert_file: Option<PathBuf>,
+    /// Private key for the subscriber, defaults to the one given by --key-file
+    #[structopt(
+        long = "subscribe-key-file",
+        env = "SUBSCRIBE_KEY_FILE",
+        parse(from_os_str)
+    )]
+    subscribe_key_file: Option<PathBuf>,
+    /// Accept any broker certificate and host name
+    #[structopt(long = "insecure")]
+    insecure: bool,
+    /// Protocol to offer with ALPN; repeat for more
+    #[structopt(long = "alpn")]
+    alpn: Vec<String>,
+    /// Username to publish as; may be an expression
+    #[structopt(long = "username", env = "MQTT_USERNAME")]
+    username: Option<String>,
+    /// Password to publish with; may be an expression
+    #[structopt(long = "password", env = "MQTT_PASSWORD", hide_env_values = true)]
+    password: Option<String>,
+    /// File to read the password to publish with from
+    #[structopt(long = "password-file", env = "MQTT_PASSWORD_FILE")]
+    password_file: Option<String>,
+    /// Username to subscribe as, defaults to the one given by --username
+    /// expanded for the subscriber
+    #[structopt(long = "subscribe-username", env = "SUBSCRIBE_USERNAME")]
+    subscribe_username: Option<String>,
+    /// Password to subscribe with; may be an expression
+    #[structopt(
+        long = "subscribe-password",
+        env = "SUBSCRIBE_PASSWORD",
+        hide_env_values = true
+    )]
+    subscribe_password: Option<String>,
+    /// File to read the password to subscribe with from
+    #[structopt(long = "subscribe-password-file", env = "SUBSCRIBE_PASSWORD_FILE")]
+    subscribe_password_file: Option<String>,
+    /// Give up on the whole scenario after this many seconds
+    #[structopt(long = "deadline", env = "DEADLINE", parse(try_from_str = duration_from_str))]
+    deadline: Option<Duration>,
+    /// Give up when the subscriber receives no message for this many seconds
+    #[structopt(long = "idle-timeout", env = "IDLE_TIMEOUT", parse(try_from_str = duration_from_str))]
+    idle_timeout: Option<Duration>,
+    /// Parameter for expansion, as key=value for an int, float, bool or
+    /// otherwise a string, or key:type=value where type is str, int, float,
+    /// bool or json
+    #[structopt(long = "parameter", parse(try_from_str = parameters::parse))]
+    parameters: Vec<(String, Value)>,
+    /// Integer parameter for expansion, as key=value
+    #[structopt(long = "parameter-int", parse(try_from_str = parameters::parse_int))]
+    int_parameters: Vec<(String, Value)>,
+    /// Parameter for expansion with a JSON value, as key=value
+    #[structopt(long = "parameter-json", parse(try_from_str = parameters::parse_json))]
+    json_parameters: Vec<(String, Value)>,
+    /// JSON, YAML or TOML file with a table of parameters
+    #[structopt(long = "parameter-file", env = "PARAMETER_FILE", parse(from_os_str))]
+    parameter_file: Option<PathBuf>,
+    /// Take parameters from environment variables starting with this prefix,
+    /// named by the rest of the variable name in lower case and typed like
+    /// --parameter values
+    #[structopt(long = "parameter-env-prefix", env = "PARAMETER_ENV_PREFIX")]
+    parameter_env_prefix: Option<String>,
+    /// Fail unless every message arrives on this topic, expanded with the
+    /// message variables and `published` for the topic it was published to
+    #[structopt(long = "expect-topic", env = "EXPECT_TOPIC")]
+    expect_topic: Option<String>,
+    /// Fail unless every received payload, less its verification marker,
+    /// equals this template expanded with the message variables
+    #[structopt(long = "expect-payload", env = "EXPECT_PAYLOAD")]
+    expect_payload: Option<String>,
+    /// Fail unless every received payload, less its verification marker,
+    /// matches this regular expression
+    #[structopt(long = "expect-payload-regex", env = "EXPECT_PAYLOAD_REGEX")]
+    expect_payload_regex: Option<Regex>,
+    /// Fail if median latency in seconds exceeds this
+    #[structopt(long = "max-p50", env = "MAX_P50", parse(try_from_str = duration_from_str))]
+    max_p50: Option<Duration>,
+    /// Fail if 95th percentile latency in seconds exceeds this
+    #[structopt(long = "max-p95", env = "MAX_P95", parse(try_from_str = duration_from_str))]
+    max_p95: Option<Duration>,
+    /// Fail if 99th percentile latency in seconds exceeds this
+    #[structopt(long = "max-p99", env = "MAX_P99", parse(try_from_str = duration_from_str))]
+    max_p99: Option<Duration>,
+    /// Fail if any message latency in seconds exceeds this
+    #[structopt(long = "max-latency", env = "MAX_LATENCY", parse(try_from_str = duration_from_str))]
+    max_latency: Option<Duration>,
+    /// Format of the report printed when the scenario has run
+    #[structopt(
+        long = "report-format",
+        env = "REPORT_FORMAT",
+        default_value = "text",
+        possible_values = &["text", "json", "junit"]
+    )]
+    report_format: report::Format,
+}
+
+fn tls_options(
+    opt: &Opt,
+    uri: &str,
+    cert_file: Option<&PathBuf>,
+    key_file: Option<&PathBuf>,
+) -> Option<tls::TlsOptions> {
+    let tls = tls::TlsOptions {
+        ca_file: opt.ca_file.clone(),
+        cert_file: cert_file.cloned(),
+        key_file: key_file.cloned(),
+        insecure: opt.insecure,
+        alpn: opt.alpn.clone(),
+    };
+    if tls.is_default() && !tls::TlsOptions::required_by(uri) {
+        None
+    } else {
+        Some(tls)
+    }
+}
+
+fn credentials(
+    context: Rc<context::OverlayContext>,
+    username: Option<&String>,
+    password: Option<&String>,
+    password_file: Option<&String>,
+) -> Result<Option<credentials::Credentials>, errors::MqttVerifyError> {
+    username
+        .map(|username| {
+            credentials::Credentials::expand(
+                context,
+                username,
+                password.map(String::as_str),
+                password_file.map(String::as_str),
+            )
+        })
+        .transpose()
+}
+
+fn named_context(
+    root: &Rc<context::OverlayContext>,
+    role: &str,
+    name: &str,
+) -> Rc<context::OverlayContext> {
+    let mut context = context::OverlayContext::subcontext(root.clone());
+    Rc::get_mut(&mut context)
+        .unwrap()
+        .insert(role.to_owned(), Value::String(name.to_owned()));
+    context
+}
+
+/// The analyzer chain verifying everything published, built afresh for each
+/// subscriber or group.
+fn make_cli_sinks(
+    opt: &Opt,
+    context: Rc<context::OverlayContext>,
+    sources: &[source::VerifiableSource],
+    published: &analyzers::PublishedTopics,
+    wills: &[will::Will],
+) -> Result<Vec<Box<dyn analyzers::Analyzer>>, errors::MqttVerifyError> {
+    if opt.subscribe_after_publishing {
+        return Ok(vec![Box::new(analyzers::RetainedAnalyzer::for_sources(
+            sources.iter(),
+        )?)]);
+    }
+    let subscribe_qos = opt.subscribe_qos.unwrap_or(opt.qos);
+    let delivery = analyzers::Delivery::for_qos(cmp::min(opt.qos, subscribe_qos));
+    let mut sinks: Vec<Box<dyn analyzers::Analyzer>> = Vec::new();
+    for source in sources {
+        sinks.push(Box::new(analyzers::SessionIdFilter::new(
+            source.id().to_owned(),
+            Box::new(analyzers::SequenceAnalyzer::new(1, delivery)),
+        )));
+    }
+    let total = opt.publishers as usize * (opt.frequency * opt.length) as usize;
+    let thresholds = analyzers::LatencyThresholds {
+        p50: opt.max_p50,
+        p95: opt.max_p95,
+        p99: opt.max_p99,
+        max: opt.max_latency,
+    };
+    if thresholds.is_set() {
+        sinks.push(Box::new(analyzers::LatencyAnalyzer::new(total, thresholds)));
+    }
+    let mut matchers = Vec::new();
+    if let Some(ref template) = opt.expect_payload {
+        matchers.push(analyzers::PayloadMatch::Template(
+            context::OverlayContext::value_for(context.clone(), template)?,
+        ));
+    }
+    if let Some(ref regex) = opt.expect_payload_regex {
+        matchers.push(analyzers::PayloadMatch::Regex(Box::new(regex.clone())));
+    }
+    if let Some(ref expected) = opt.expect_topic {
+        let analyzer = analyzers::TopicAnalyzer::new(
+            context::OverlayContext::value_for(context, expected)?,
+            published.clone(),
+            Box::new(analyzers::CountingAnalyzer::new(total)),
+        );
+        analyzer.validate()?;
+        sinks.push(Box::new(analyzer));
+    }
+    for matcher in matchers {
+        matcher.validate()?;
+        sinks.push(Box::new(analyzers::PayloadMatchAnalyzer::new(
+            matcher,
+            Box::new(analyzers::CountingAnalyzer::new(total)),
+        )));
+    }
+    for will in wills {
+        sinks.push(Box::new(analyzers::WillAnalyzer::new(
+            will.clone(),
+            opt.sever,
+        )));
+    }
+    Ok(sinks)
+}
+
+/// MQTT v5 properties of the messages from a publisher, if any are given.
+fn message_properties(
+    opt: &Opt,
+    context: Rc<context::OverlayContext>,
+) -> Result<Option<v5::MessageProperties>, errors::MqttVerifyError> {
+    if opt.user_properties.is_empty()
+        && opt.message_expiry.is_none()
+        && opt.content_type.is_none()
+        && opt.response_topic.is_none()
+        && opt.correlation_data.is_none()
+    {
+        return Ok(None);
+    }
+    let template = |value: &String| context::OverlayContext::value_for(context.clone(), value);
+    Ok(Some(v5::MessageProperties {
+        user: opt
+            .user_properties
+            .iter()
+            .map(|(key, value)| Ok((key.clone(), template(value)?)))
+            .collect::<Result<_, errors::MqttVerifyError>>()?,
+        message_expiry: opt.message_expiry,
+        content_type: opt.content_type.clone(),
+        response_topic: opt.response_topic.as_ref().map(template).transpose()?,
+        correlation_data: opt.correlation_data.as_ref().map(template).transpose()?,
+    }))
+}
+
+/// A publisher connection for `sources`, with its client id and credentials
+/// expanded in `context` naming the publisher it serves.
+fn make_cli_publisher(
+    opt: &Opt,
+    context: Rc<context::OverlayContext>,
+    name: String,
+    sources: Vec<source::VerifiableSource>,
+    will: Option<will::Will>,
+    backoff: &backoff::Backoff,
+) -> Result<scenario::Publisher, errors::MqttVerifyError> {
+    let uri = opt.publish_uri.as_ref().unwrap();
+    let tls = tls_options(opt, uri, opt.cert_file.as_ref(), opt.key_file.as_ref());
+    let relay = match (opt.sever, &tls) {
+        (false, _) => None,
+        (true, None) => Some(will::Relay::bind(uri)?),
+        (true, Some(_)) => {
+            return Err(errors::MqttVerifyError::SeverOverTls {
+                uri: uri.to_owned(),
+            })
+        }
+    };
+    let client_id = match opt.publish_client_id {
+        Some(ref client_id) => {
+            context::OverlayContext::value_for(context.clone(), client_id)?.value()?
+        }
+        None => String::new(),
+    };
+    Ok(scenario::Publisher {
+        name,
+        client: mqtt_verify::create_client(
+            relay.as_ref().map_or(uri, |relay| relay.uri()),
+            &client_id,
+            opt.mqtt_version.unwrap_or(mqtt::MQTT_VERSION_DEFAULT),
+        ),
+        initial_timeout: opt.initial_timeout,
+        tls,
+        credentials: credentials(
+            context,
+            opt.username.as_ref(),
+            opt.password.as_ref(),
+            opt.password_file.as_ref(),
+        )?,
+        backoff: backoff.clone(),
+        reconnect: opt.reconnect,
+        will,
+        relay,
+        sources,
+    })
+}
+
+/// All parameters in increasing precedence: environment, file, options.
+fn collect_parameters(opt: &Opt) -> Result<Vec<(String, Value)>, errors::MqttVerifyError> {
+    let mut collected = Vec::new();
+    if let Some(ref prefix) = opt.parameter_env_prefix {
+        collected.extend(parameters::from_env(prefix));
+    }
+    if let Some(ref path) = opt.parameter_file {
+        collected.extend(parameters::from_file(path)?);
+    }
+    collected.extend(opt.parameters.iter().cloned());
+    collected.extend(opt.int_parameters.iter().cloned());
+    collected.extend(opt.json_parameters.iter().cloned());
+    Ok(collected)
 }
 
 pub fn make_cli_scenario(opt: &Opt) -> Result<scenario::Scenario, errors::MqttVerifyError> {
     let mut root = context::OverlayContext::root();
-    for (k, v) in &opt.parameters {
-        Rc::get_mut(&mut root)
-            .unwrap()
-            .insert(k.clone(), Value::String(v.clone()));
+    for (k, v) in collect_parameters(opt)? {
+        Rc::get_mut(&mut root).unwrap().insert(k, v);
     }
     let mut sources = Vec::new();
-    let mut sinks: Vec<Box<dyn analyzers::Analyzer>> = Vec::new();
+    let mut published = analyzers::PublishedTopics::new();
+    let mut named = Vec::new();
     for i in 1..=opt.publishers {
-        let mut context = context::OverlayContext::subcontext(root.clone());
-        Rc::get_mut(&mut context)
-            .unwrap()
-            .insert("publisher".to_owned(), Value::String(format!("p-{}", i)));
-        sources.push(source::VerifiableSource::new(
+        let name = format!("p-{}", i);
+        let context = named_context(&root, "publisher", &name);
+        let values = context::OverlayContext::for_source(context.clone());
+        let mut source = source::VerifiableSource::new(
             format!("{}", i),
-            context::OverlayContext::value_for(context.clone(), &opt.topic)?,
+            context::OverlayContext::value_for(values.clone(), &opt.topic)?,
             (opt.frequency * opt.length) as usize,
             opt.frequency,
-        ));
-        sinks.push(Box::new(analyzers::SessionIdFilter::new(
-            format!("{}", i),
-            Box::new(analyzers::CountingAnalyzer::new(
-                (opt.frequency * opt.length) as usize,
-            )),
-        )));
+        )
+        .with_qos(opt.qos)
+        .with_retained(opt.retain);
+        if let Some(ref payload) = opt.payload {
+            source =
+                source.with_payload(context::OverlayContext::value_for(values.clone(), payload)?);
+        }
+        if let Some(properties) = message_properties(opt, values)? {
+            source = source.with_properties(properties);
+        }
+        source.validate()?;
+        published.insert(source.id().to_owned(), source.topic.clone());
+        sources.push(source);
+        named.push((name, context));
     }
-    Ok(scenario::Scenario {
-        publishers: vec![scenario::Publisher {
-            client: mqtt_verify::client(&opt.publish_uri),
-            initial_timeout: opt.initial_timeout,
-            sources: sources,
-        }],
-        subscribers: vec![scenario::Subscriber {
-            client: mqtt_verify::client(&opt.subscribe_uri),
+    // A connection serving a single publisher is named after it, while one
+    // shared by several cannot expand its settings for any one of them.
+    let shared = !opt.connection_per_publisher && opt.publishers > 1;
+    if shared {
+        let per_connection = vec![
+            ("--publish-client-id", opt.publish_client_id.as_ref()),
+            ("--username", opt.username.as_ref()),
+            ("--password", opt.password.as_ref()),
+            ("--password-file", opt.password_file.as_ref()),
+            ("--will-topic", opt.will_topic.as_ref()),
+            ("--will-payload", Some(&opt.will_payload)),
+        ];
+        for (option, template) in per_connection {
+            let template = match template {
+                Some(template) => template,
+                None => continue,
+            };
+            if context::precompile(template)?
+                .variables()
+                .iter()
+                .any(|variable| variable == "publisher")
+            {
+                return Err(errors::MqttVerifyError::SharedConnectionTemplate {
+                    option: option.to_owned(),
+                    template: template.clone(),
+                });
+            }
+        }
+    }
+    let connections = if shared {
+        vec![("publisher".to_owned(), root.clone())]
+    } else {
+        named
+    };
+    let wills = match opt.will_topic {
+        Some(ref topic) => connections
+            .iter()
+            .map(|(_, context)| {
+                let expand =
+                    |value| context::OverlayContext::value_for(context.clone(), value)?.value();
+                Ok(will::Will {
+                    topic: expand(topic)?,
+                    payload: expand(&opt.will_payload)?,
+                    qos: opt.will_qos,
+                    retained: false,
+                })
+            })
+            .collect::<Result<Vec<_>, errors::MqttVerifyError>>()?,
+        None => Vec::new(),
+    };
+    // The absence of a will is only established once the subscriber gives up
+    if !opt.sever && opt.deadline.is_none() && opt.idle_timeout.is_none() {
+        if let Some(will) = wills.first() {
+            return Err(errors::MqttVerifyError::UnboundedCheck {
+                check: format!("no will on {}", will.topic),
+            });
+        }
+    }
+    let subscribe_topic = opt
+        .subscribe_topic
+        .clone()
+        .unwrap_or_else(|| opt.topic.clone());
+    let mut topics = vec![(
+        match opt.subscribe_share {
+            Some(ref group) => v5::shared(group, &subscribe_topic),
+            None => subscribe_topic,
+        },
+        opt.subscribe_qos.unwrap_or(opt.qos),
+    )];
+    for will in &wills {
+        if !topics.iter().any(|(topic, _)| *topic == will.topic) {
+            topics.push((will.topic.clone(), will.qos));
+        }
+    }
+    let subscribe_uri = opt.subscribe_uri.as_ref().unwrap();
+    let mqtt_version = opt.mqtt_version.unwrap_or(mqtt::MQTT_VERSION_DEFAULT);
+    let backoff = backoff::Backoff::new(opt.backoff_initial, opt.backoff_max, opt.backoff_jitter)?;
+    let group = match opt.subscribe_share {
+        Some(ref name) if opt.subscribers > 1 => Some(name),
+        _ => None,
+    };
+    let mut subscribers = Vec::new();
+    for i in 1..=opt.subscribers {
+        let name = if opt.subscribers == 1 {
+            "subscriber".to_owned()
+        } else {
+            format!("subscriber-{}", i)
+        };
+        let context = named_context(&root, "subscriber", &name);
+        let session = opt
+            .offline_after
+            .map(|offline_after| scenario::PersistentSession {
+                offline_after,
+                offline_for: opt.offline_for,
+            });
+        let client_id = match (&opt.subscribe_client_id, &session) {
+            (Some(client_id), _) => {
+                context::OverlayContext::value_for(context.clone(), client_id)?.value()?
+            }
+            (None, Some(_)) => format!("mqtt-verify-{}", name),
+            (None, None) => String::new(),
+        };
+        let subscribe_credentials = match opt.subscribe_username {
+            Some(ref username) => credentials(
+                context.clone(),
+                Some(username),
+                opt.subscribe_password.as_ref(),
+                opt.subscribe_password_file.as_ref(),
+            )?,
+            None => credentials(
+                context.clone(),
+                opt.username.as_ref(),
+                opt.password.as_ref(),
+                opt.password_file.as_ref(),
+            )?,
+        };
+        let sinks = match group {
+            Some(_) => Vec::new(),
+            None => make_cli_sinks(opt, context, &sources, &published, &wills)?,
+        };
+        subscribers.push(scenario::Subscriber {
+            name,
+            client: mqtt_verify::create_client(subscribe_uri, &client_id, mqtt_version),
             initial_timeout: opt.initial_timeout,
-            topics: vec![opt.topic.clone()],
-            sinks: sinks,
-        }],
-    })
+            tls: tls_options(
+                opt,
+                subscribe_uri,
+                opt.subscribe_cert_file.as_ref().or(opt.cert_file.as_ref()),
+                opt.subscribe_key_file.as_ref().or(opt.key_file.as_ref()),
+            ),
+            credentials: subscribe_credentials,
+            backoff: backoff.clone(),
+            reconnect: opt.reconnect,
+            topics: topics
+                .iter()
+                .map(|(topic, qos)| scenario::Subscription {
+                    topic: topic.clone(),
+                    qos: *qos,
+                })
+                .collect(),
+            sinks,
+            idle_timeout: opt.idle_timeout,
+            after_publishing: opt.subscribe_after_publishing,
+            session,
+        });
+    }
+    let mut groups = Vec::new();
+    if let Some(name) = group {
+        if opt.offline_after.is_some() || opt.subscribe_after_publishing {
+            return Err(errors::MqttVerifyError::MalformedGroup {
+                group: name.clone(),
+                reason: "members cannot go offline or wait for the publishers".to_owned(),
+            });
+        }
+        groups.push(scenario::SubscriberGroup {
+            name: name.clone(),
+            sinks: make_cli_sinks(
+                opt,
+                named_context(&root, "group", name),
+                &sources,
+                &published,
+                &wills,
+            )?,
+            members: std::mem::take(&mut subscribers),
+            min_share: opt.min_share,
+            idle_timeout: opt.idle_timeout,
+        });
+    }
+    let per_connection = if shared { sources.len() } else { 1 };
+    let mut sources = sources.into_iter();
+    let mut wills = wills.into_iter();
+    let publishers = connections
+        .into_iter()
+        .map(|(name, context)| {
+            let sources = sources.by_ref().take(per_connection).collect();
+            make_cli_publisher(opt, context, name, sources, wills.next(), &backoff)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let scenario = scenario::Scenario {
+        publishers,
+        subscribers,
+        groups,
+        deadline: opt.deadline,
+        keep_retained: opt.keep_retained,
+    };
+    scenario.validate()?;
+    Ok(scenario)
 }
 
 fn main() -> Result<(), errors::MqttVerifyError> {
     let opt = Opt::from_args();
-    let scenario = make_cli_scenario(&opt)?;
-
-    task::block_on(async {
-        let mut results = mqtt_verify::run_scenario(scenario);
-        while let Some(result) = results.next().await {
-            match result {
-                Ok(_) => (),
-                Err(err) => return Err(err),
-            }
+    let scenario = match opt.scenario {
+        Some(ref path) => {
+            definition::ScenarioDefinition::from_file(path)?.build(&collect_parameters(&opt)?)?
         }
-        Ok(())
-    })
+        None => make_cli_scenario(&opt)?,
+    };
+
+    let report = task::block_on(mqtt_verify::run_scenario(scenario));
+    println!("{}", report.render(opt.report_format));
+    if !report.success() {
+        process::exit(1);
+    }
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
     use super::Opt;
+    use mqtt_verify::backoff;
     use mqtt_verify::errors;
+    use paho_mqtt as mqtt;
+    use std::path::PathBuf;
+    use std::time::Duration;
     use structopt::StructOpt;
 
     fn basic_options(extra: Vec<&str>) -> Opt {
@@ -138,7 +746,7 @@ mod tests {
         let publisher = scenario.publishers.get(0).unwrap();
         assert_eq!(1, publisher.sources.len());
         let source = publisher.sources.get(0).unwrap();
-        assert_eq!("p-1".to_owned(), source.topic.value());
+        assert_eq!("p-1".to_owned(), source.topic.value()?);
         Ok(())
     }
 
@@ -148,7 +756,417 @@ mod tests {
         let scenario = super::make_cli_scenario(&opt)?;
         let publisher = scenario.publishers.get(0).unwrap();
         let source = publisher.sources.get(0).unwrap();
-        assert_eq!("bar".to_owned(), source.topic.value());
+        assert_eq!("bar".to_owned(), source.topic.value()?);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_typed_parameters() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--topic",
+            "{{prefix}}/{{shard * 2}}/{{enabled}}",
+            "--parameter",
+            "prefix=s",
+            "--parameter-int",
+            "shard=3",
+            "--parameter-json",
+            "enabled=true",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let source = &scenario.publishers[0].sources[0];
+        assert_eq!("s/6/true".to_owned(), source.topic.value()?);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_subscribes_to_filter() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--topic",
+            "sensors/{{seq % 100}}/temp",
+            "--subscribe-topic",
+            "sensors/+/temp",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        assert_eq!("sensors/+/temp", scenario.subscribers[0].topics[0].topic);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_separate_subscriber_identity() -> Result<(), errors::MqttVerifyError>
+    {
+        let opt = basic_options(vec![
+            "--cert-file",
+            "publisher.crt",
+            "--subscribe-cert-file",
+            "subscriber.crt",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let publisher_tls = scenario.publishers[0].tls.as_ref().unwrap();
+        let subscriber_tls = scenario.subscribers[0].tls.as_ref().unwrap();
+        assert_eq!(
+            Some(PathBuf::from("publisher.crt")),
+            publisher_tls.cert_file
+        );
+        assert_eq!(
+            Some(PathBuf::from("subscriber.crt")),
+            subscriber_tls.cert_file
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_credentials() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--publishers",
+            "2",
+            "--connection-per-publisher",
+            "--username",
+            "user-{{publisher}}",
+            "--password",
+            "{{secret}}",
+            "--parameter",
+            "secret=hunter2",
+            "--subscribe-username",
+            "{{subscriber}}",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let usernames: Vec<&str> = scenario
+            .publishers
+            .iter()
+            .map(|publisher| publisher.credentials.as_ref().unwrap().username.as_str())
+            .collect();
+        assert_eq!(vec!["user-p-1", "user-p-2"], usernames);
+        let publisher = scenario.publishers[0].credentials.as_ref().unwrap();
+        let subscriber = scenario.subscribers[0].credentials.as_ref().unwrap();
+        assert_eq!(Some("hunter2".to_owned()), publisher.password);
+        assert_eq!("subscriber", subscriber.username);
+        assert_eq!(None, subscriber.password);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_without_tls() -> Result<(), errors::MqttVerifyError> {
+        let scenario = super::make_cli_scenario(&basic_options(vec![]))?;
+        assert!(scenario.publishers[0].tls.is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_expects_payload() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--payload",
+            "{{seq}}",
+            "--expect-payload",
+            "{{seq}}",
+            "--expect-payload-regex",
+            "^\\d+$",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let sinks = &scenario.subscribers[0].sinks;
+        assert!(sinks[sinks.len() - 2]
+            .describe()
+            .starts_with("payload equal to {{seq}}"));
+        assert!(sinks[sinks.len() - 1]
+            .describe()
+            .starts_with("payload matching /^\\d+$/"));
+        assert!(Opt::from_iter_safe(vec!["mqtt-verify", "--expect-payload-regex", "(",]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_checks_latency_on_request() -> Result<(), errors::MqttVerifyError> {
+        let scenario = super::make_cli_scenario(&basic_options(vec![]))?;
+        assert_eq!(1, scenario.subscribers[0].sinks.len());
+        let scenario = super::make_cli_scenario(&basic_options(vec!["--max-p99", "0.5"]))?;
+        let sinks = &scenario.subscribers[0].sinks;
+        assert_eq!(2, sinks.len());
+        assert_eq!("latency of 10 messages", sinks[1].describe());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_expects_topic() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--topic",
+            "a/{{publisher}}",
+            "--expect-topic",
+            r#"{{replace(published, "a/", "b/")}}"#,
+        ]);
+        let mut scenario = super::make_cli_scenario(&opt)?;
+        let message = scenario.publishers[0].sources[0].next_message().unwrap()?;
+        let bridged = mqtt::Message::new("b/p-1", message.payload(), 0);
+        let sinks = &mut scenario.subscribers[0].sinks;
+        let topic_sink = sinks
+            .iter_mut()
+            .find(|sink| sink.describe().starts_with("topic"));
+        let topic_sink = topic_sink.unwrap();
+        assert!(topic_sink.analyze(bridged).is_ok());
+        assert!(topic_sink.analyze(message).is_ok());
+        assert_eq!(Some("matched 1/2 topics".to_owned()), topic_sink.summary());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_verifies_retained() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--topic",
+            "status/{{publisher}}",
+            "--retain",
+            "--subscribe-after-publishing",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        assert!(scenario.publishers[0].sources[0]
+            .next_message()
+            .unwrap()?
+            .retained());
+        let subscriber = &scenario.subscribers[0];
+        assert!(subscriber.after_publishing);
+        assert_eq!(1, subscriber.sinks.len());
+        assert_eq!("retained on 1 topics", subscriber.sinks[0].describe());
+        assert!(!scenario.keep_retained);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_will() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--will-topic",
+            "status/{{publisher}}",
+            "--will-payload",
+            "gone",
+            "--sever",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let publisher = &scenario.publishers[0];
+        assert_eq!("status/p-1", publisher.will.as_ref().unwrap().topic);
+        assert!(publisher.relay.is_some());
+        let subscriber = &scenario.subscribers[0];
+        assert_eq!("status/p-1", subscriber.topics[1].topic);
+        let sinks = &subscriber.sinks;
+        assert_eq!("will on status/p-1", sinks[sinks.len() - 1].describe());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_bounds_the_absent_will_check() {
+        let opt = basic_options(vec!["--will-topic", "status"]);
+        match super::make_cli_scenario(&opt) {
+            Err(errors::MqttVerifyError::UnboundedCheck { check }) => {
+                assert_eq!("no will on status", check)
+            }
+            _ => panic!("Expected a deadline to be required"),
+        }
+        let opt = basic_options(vec!["--will-topic", "status", "--idle-timeout", "5"]);
+        let scenario = super::make_cli_scenario(&opt).unwrap();
+        let sinks = &scenario.subscribers[0].sinks;
+        assert_eq!("no will on status", sinks[sinks.len() - 1].describe());
+    }
+
+    #[test]
+    fn make_cli_scenario_rejects_severing_tls() {
+        let opt = Opt::from_iter(vec![
+            "./mqtt-verify",
+            "--publish-uri",
+            "ssl://localhost:8883",
+            "--subscribe-uri",
+            "tcp://localhost:1883",
+            "--sever",
+        ]);
+        match super::make_cli_scenario(&opt) {
+            Err(errors::MqttVerifyError::SeverOverTls { uri }) => {
+                assert_eq!("ssl://localhost:8883", uri)
+            }
+            _ => panic!("Expected severing TLS to be rejected"),
+        }
+    }
+
+    #[test]
+    fn make_cli_scenario_with_persistent_session() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec!["--qos", "1", "--offline-after", "2.5"]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let subscriber = &scenario.subscribers[0];
+        assert_eq!(
+            "mqtt-verify-subscriber",
+            mqtt_verify::client_id(&subscriber.client)
+        );
+        let session = subscriber.session.as_ref().unwrap();
+        assert_eq!(Duration::from_millis(2500), session.offline_after);
+        assert_eq!(Duration::from_secs(1), session.offline_for);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_fans_out_to_subscribers() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--subscribers",
+            "3",
+            "--subscribe-client-id",
+            "verify-{{subscriber}}",
+            "--expect-payload",
+            "",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        assert!(scenario.groups.is_empty());
+        let subscribers = &scenario.subscribers;
+        assert_eq!(3, subscribers.len());
+        assert_eq!("subscriber-2", subscribers[1].name);
+        assert_eq!(
+            "verify-subscriber-3",
+            mqtt_verify::client_id(&subscribers[2].client)
+        );
+        for subscriber in subscribers {
+            assert_eq!(2, subscriber.sinks.len());
+            assert_eq!("1", subscriber.topics[0].topic);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_connection_per_publisher() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--publishers",
+            "3",
+            "--connection-per-publisher",
+            "--publish-client-id",
+            "load-{{publisher}}",
+            "--will-topic",
+            "status/{{publisher}}",
+            "--deadline",
+            "30",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let publishers = &scenario.publishers;
+        assert_eq!(3, publishers.len());
+        assert_eq!("p-2", publishers[1].name);
+        assert_eq!("load-p-3", mqtt_verify::client_id(&publishers[2].client));
+        assert_eq!("status/p-3", publishers[2].will.as_ref().unwrap().topic);
+        for publisher in publishers {
+            assert_eq!(1, publisher.sources.len());
+        }
+        let topics: Vec<&str> = scenario.subscribers[0]
+            .topics
+            .iter()
+            .map(|subscription| subscription.topic.as_str())
+            .collect();
+        assert_eq!(vec!["1", "status/p-1", "status/p-2", "status/p-3"], topics);
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_shares_a_publisher_connection() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec!["--publishers", "3"]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        assert_eq!(1, scenario.publishers.len());
+        let publisher = &scenario.publishers[0];
+        assert_eq!("publisher", publisher.name);
+        assert_eq!("", mqtt_verify::client_id(&publisher.client));
+        assert_eq!(3, publisher.sources.len());
+        let opt = basic_options(vec!["--publishers", "3", "--username", "{{publisher}}"]);
+        match super::make_cli_scenario(&opt) {
+            Err(errors::MqttVerifyError::SharedConnectionTemplate { option, template }) => {
+                assert_eq!("--username", option);
+                assert_eq!("{{publisher}}", template);
+            }
+            _ => panic!("Expected a per-publisher template to be rejected"),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_rejects_duplicate_client_ids() {
+        let opt = basic_options(vec![
+            "--publishers",
+            "2",
+            "--connection-per-publisher",
+            "--publish-client-id",
+            "load",
+        ]);
+        match super::make_cli_scenario(&opt) {
+            Err(errors::MqttVerifyError::DuplicateClientId { name, first, .. }) => {
+                assert_eq!("p-2", name);
+                assert_eq!("p-1", first);
+            }
+            _ => panic!("Expected a duplicate client id"),
+        }
+    }
+
+    #[test]
+    fn make_cli_scenario_with_reconnect() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--reconnect",
+            "--backoff-initial",
+            "0.5",
+            "--backoff-jitter",
+            "0",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let expected =
+            backoff::Backoff::new(Duration::from_millis(500), Duration::from_secs(2), 0.0)?;
+        assert!(scenario.publishers[0].reconnect);
+        assert_eq!(expected, scenario.publishers[0].backoff);
+        assert!(scenario.subscribers[0].reconnect);
+        assert_eq!(expected, scenario.subscribers[0].backoff);
+        let opt = basic_options(vec!["--backoff-jitter", "2"]);
+        assert!(super::make_cli_scenario(&opt).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_groups_shared_subscribers() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--subscribers",
+            "2",
+            "--subscribe-share",
+            "workers",
+            "--min-share",
+            "0.8",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        assert!(scenario.subscribers.is_empty());
+        let group = &scenario.groups[0];
+        assert_eq!("workers", group.name);
+        assert_eq!(0.8, group.min_share);
+        assert_eq!(2, group.members.len());
+        assert_eq!("$share/workers/1", group.members[1].topics[0].topic);
+        assert!(group.members[0].sinks.is_empty());
+        assert_eq!(1, group.sinks.len());
+        Ok(())
+    }
+
+    #[test]
+    fn make_cli_scenario_with_mqtt_v5() -> Result<(), errors::MqttVerifyError> {
+        let opt = basic_options(vec![
+            "--mqtt-version",
+            "5",
+            "--user-property",
+            "origin={{publisher}}",
+            "--content-type",
+            "text/plain",
+            "--correlation-data",
+            "{{seq}}",
+            "--subscribe-share",
+            "workers",
+        ]);
+        let scenario = super::make_cli_scenario(&opt)?;
+        let publisher = &scenario.publishers[0];
+        assert_eq!(mqtt::MQTT_VERSION_5, publisher.client.mqtt_version());
+        let message = publisher.sources[0].next_message().unwrap()?;
+        let properties = message.properties();
+        assert_eq!(
+            Some(("origin".to_owned(), "p-1".to_owned())),
+            properties.get_string_pair_at(mqtt::PropertyCode::UserProperty, 0)
+        );
+        assert_eq!(
+            Some("text/plain".to_owned()),
+            properties.get_string(mqtt::PropertyCode::ContentType)
+        );
+        assert_eq!(
+            Some(b"1".to_vec()),
+            properties.get_binary(mqtt::PropertyCode::CorrelationData)
+        );
+        let subscriber = &scenario.subscribers[0];
+        assert_eq!(mqtt::MQTT_VERSION_5, subscriber.client.mqtt_version());
+        assert_eq!("$share/workers/1", subscriber.topics[0].topic);
         Ok(())
     }
 }
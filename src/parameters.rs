@@ -0,0 +1,230 @@
+use crate::errors::MqttVerifyError;
+use evalexpr::Value;
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+pub(crate) fn split_on_equal(input: &str) -> Result<(&str, &str), MqttVerifyError> {
+    let pair: Vec<&str> = input.splitn(2, '=').collect();
+    if pair.len() == 2 {
+        Ok((pair[0], pair[1]))
+    } else {
+        Err(MqttVerifyError::MalformedParameter {
+            input: input.to_owned(),
+        })
+    }
+}
+
+fn malformed(value: &str) -> MqttVerifyError {
+    MqttVerifyError::MalformedValue {
+        value: value.to_owned(),
+    }
+}
+
+/// Convert a JSON value to the corresponding expression value. Arrays become
+/// tuples; objects have no counterpart.
+pub fn from_json(json: &serde_json::Value) -> Result<Value, MqttVerifyError> {
+    Ok(match json {
+        serde_json::Value::Null => Value::Empty,
+        serde_json::Value::Bool(b) => Value::Boolean(*b),
+        serde_json::Value::Number(n) => match n.as_i64() {
+            Some(i) => Value::Int(i),
+            None => Value::Float(n.as_f64().ok_or_else(|| malformed(&n.to_string()))?),
+        },
+        serde_json::Value::String(s) => Value::String(s.clone()),
+        serde_json::Value::Array(values) => {
+            Value::Tuple(values.iter().map(from_json).collect::<Result<_, _>>()?)
+        }
+        serde_json::Value::Object(_) => return Err(malformed(&json.to_string())),
+    })
+}
+
+fn typed(kind: &str, value: &str) -> Result<Value, MqttVerifyError> {
+    match kind {
+        "str" | "string" => Ok(Value::String(value.to_owned())),
+        "int" => value.parse().map(Value::Int).map_err(|_| malformed(value)),
+        "float" => value
+            .parse()
+            .map(Value::Float)
+            .map_err(|_| malformed(value)),
+        "bool" => value
+            .parse()
+            .map(Value::Boolean)
+            .map_err(|_| malformed(value)),
+        "json" => from_json(&serde_json::from_str(value).map_err(|_| malformed(value))?),
+        _ => Err(malformed(kind)),
+    }
+}
+
+/// An int, float or bool if `value` reads as one, otherwise a string.
+fn inferred(value: &str) -> Value {
+    if let Ok(i) = value.parse() {
+        Value::Int(i)
+    } else if let Some(f) = value.parse::<f64>().ok().filter(|f| f.is_finite()) {
+        Value::Float(f)
+    } else if let Ok(b) = value.parse() {
+        Value::Boolean(b)
+    } else {
+        Value::String(value.to_owned())
+    }
+}
+
+/// Parse `key=value`, inferring the type of the value, or `key:type=value`
+/// where type is one of str, int, float, bool or json.
+pub fn parse(input: &str) -> Result<(String, Value), MqttVerifyError> {
+    let (key, value) = split_on_equal(input)?;
+    let mut key_type = key.splitn(2, ':');
+    let key = key_type.next().unwrap_or_default();
+    let value = match key_type.next() {
+        Some(kind) => typed(kind, value)?,
+        None => inferred(value),
+    };
+    Ok((key.to_owned(), value))
+}
+
+pub fn parse_int(input: &str) -> Result<(String, Value), MqttVerifyError> {
+    let (key, value) = split_on_equal(input)?;
+    Ok((key.to_owned(), typed("int", value)?))
+}
+
+pub fn parse_json(input: &str) -> Result<(String, Value), MqttVerifyError> {
+    let (key, value) = split_on_equal(input)?;
+    Ok((key.to_owned(), typed("json", value)?))
+}
+
+/// Convert a JSON object to parameters.
+fn from_json_object(
+    object: &BTreeMap<String, serde_json::Value>,
+) -> Result<Vec<(String, Value)>, MqttVerifyError> {
+    object
+        .iter()
+        .map(|(k, v)| Ok((k.clone(), from_json(v)?)))
+        .collect()
+}
+
+/// Read parameters from a JSON, YAML or TOML file holding a single table.
+pub fn from_file(path: &Path) -> Result<Vec<(String, Value)>, MqttVerifyError> {
+    let name = path.display().to_string();
+    let contents = fs::read_to_string(path).map_err(|err| MqttVerifyError::ParameterFileError {
+        path: name.clone(),
+        reason: err.to_string(),
+    })?;
+    let object: Result<BTreeMap<String, serde_json::Value>, String> =
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => serde_json::from_str(&contents).map_err(|err| err.to_string()),
+            Some("yaml") | Some("yml") => {
+                serde_yaml::from_str(&contents).map_err(|err| err.to_string())
+            }
+            Some("toml") => toml::from_str(&contents).map_err(|err| err.to_string()),
+            _ => Err("expected .json, .yaml, .yml or .toml".to_owned()),
+        };
+    let object = object.map_err(|reason| MqttVerifyError::ParameterFileError {
+        path: name.clone(),
+        reason,
+    })?;
+    from_json_object(&object)
+}
+
+/// Parameters from environment variables starting with `prefix`, named by
+/// the rest of the variable name in lower case, e.g. `MQTT_VERIFY_COUNT` is
+/// `count` with prefix `MQTT_VERIFY_`. Value types are inferred like for
+/// untyped `parse`.
+pub fn from_env(prefix: &str) -> Vec<(String, Value)> {
+    let mut parameters: Vec<(String, Value)> = env::vars()
+        .filter(|(k, _)| k.starts_with(prefix) && k.len() > prefix.len())
+        .map(|(k, v)| (k[prefix.len()..].to_lowercase(), inferred(&v)))
+        .collect();
+    parameters.sort_by(|a, b| a.0.cmp(&b.0));
+    parameters
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::errors::MqttVerifyError;
+    use evalexpr::Value;
+    use std::env;
+    use std::fs;
+
+    #[test]
+    fn parse_typed() -> Result<(), MqttVerifyError> {
+        assert_eq!(
+            ("foo".to_owned(), Value::String("bar".to_owned())),
+            super::parse("foo=bar")?
+        );
+        assert_eq!(("n".to_owned(), Value::Int(5)), super::parse("n:int=5")?);
+        assert_eq!(
+            ("x".to_owned(), Value::Float(0.5)),
+            super::parse("x:float=0.5")?
+        );
+        assert_eq!(
+            ("b".to_owned(), Value::Boolean(true)),
+            super::parse("b:bool=true")?
+        );
+        assert_eq!(
+            ("url".to_owned(), Value::String("a=b".to_owned())),
+            super::parse("url=a=b")?
+        );
+        assert_eq!(("n".to_owned(), Value::Int(5)), super::parse("n=5")?);
+        assert_eq!(("x".to_owned(), Value::Float(0.5)), super::parse("x=0.5")?);
+        assert_eq!(
+            ("b".to_owned(), Value::Boolean(false)),
+            super::parse("b=false")?
+        );
+        assert_eq!(
+            ("id".to_owned(), Value::String("007".to_owned())),
+            super::parse("id:str=007")?
+        );
+        assert_eq!(
+            ("x".to_owned(), Value::String("nan".to_owned())),
+            super::parse("x=nan")?
+        );
+        assert!(super::parse("n:int=five").is_err());
+        assert!(super::parse("n:date=2020").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_json() -> Result<(), MqttVerifyError> {
+        assert_eq!(
+            (
+                "t".to_owned(),
+                Value::Tuple(vec![Value::Int(1), Value::String("a".to_owned())])
+            ),
+            super::parse_json(r#"t=[1, "a"]"#)?
+        );
+        assert_eq!(("n".to_owned(), Value::Int(2)), super::parse_int("n=2")?);
+        assert!(super::parse_json(r#"o={"a": 1}"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn from_file() -> Result<(), MqttVerifyError> {
+        let path = env::temp_dir().join("mqtt-verify-parameters.yaml");
+        fs::write(&path, "count: 10\nrate: 2.5\nname: alpha\n").unwrap();
+        let parameters = super::from_file(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(
+            vec![
+                ("count".to_owned(), Value::Int(10)),
+                ("name".to_owned(), Value::String("alpha".to_owned())),
+                ("rate".to_owned(), Value::Float(2.5)),
+            ],
+            parameters?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn from_env() {
+        env::set_var("MQTT_VERIFY_TEST_FROM_ENV_SIZE", "3");
+        env::set_var("MQTT_VERIFY_TEST_FROM_ENV_NAME", "alpha");
+        assert_eq!(
+            vec![
+                ("name".to_owned(), Value::String("alpha".to_owned())),
+                ("size".to_owned(), Value::Int(3)),
+            ],
+            super::from_env("MQTT_VERIFY_TEST_FROM_ENV_")
+        );
+    }
+}
@@ -0,0 +1,382 @@
+use crate::errors::MqttVerifyError;
+use serde::{Serialize, Serializer};
+use std::fmt::Write;
+use std::str::FromStr;
+use std::time::Duration;
+
+fn as_secs<S: Serializer>(duration: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_f64(duration.as_secs_f64())
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Pass,
+    Fail,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SinkReport {
+    pub name: String,
+    pub verdict: Verdict,
+    pub reason: Option<String>,
+    pub summary: Option<String>,
+    /// Time from the subscriber starting until the sink reached its verdict
+    #[serde(serialize_with = "as_secs")]
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PublisherReport {
+    pub name: String,
+    pub sent: usize,
+    pub errors: Vec<String>,
+    /// Times the connection was lost and established again
+    pub reconnects: usize,
+    #[serde(serialize_with = "as_secs")]
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SubscriberReport {
+    pub name: String,
+    pub received: usize,
+    pub error: Option<String>,
+    /// Times the connection was lost and established again
+    pub reconnects: usize,
+    pub sinks: Vec<SinkReport>,
+    #[serde(serialize_with = "as_secs")]
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub publishers: Vec<PublisherReport>,
+    pub subscribers: Vec<SubscriberReport>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Text,
+    Json,
+    Junit,
+}
+
+impl FromStr for Format {
+    type Err = MqttVerifyError;
+
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "text" => Ok(Format::Text),
+            "json" => Ok(Format::Json),
+            "junit" => Ok(Format::Junit),
+            _ => Err(MqttVerifyError::MalformedValue {
+                value: input.to_owned(),
+            }),
+        }
+    }
+}
+
+fn escape_xml(input: &str) -> String {
+    input
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+impl Report {
+    pub fn failures(&self) -> usize {
+        self.subscribers
+            .iter()
+            .flat_map(|subscriber| subscriber.sinks.iter())
+            .filter(|sink| sink.verdict == Verdict::Fail)
+            .count()
+    }
+
+    pub fn errors(&self) -> usize {
+        self.publishers
+            .iter()
+            .filter(|publisher| !publisher.errors.is_empty())
+            .count()
+            + self
+                .subscribers
+                .iter()
+                .filter(|subscriber| subscriber.error.is_some())
+                .count()
+    }
+
+    pub fn success(&self) -> bool {
+        self.failures() == 0 && self.errors() == 0
+    }
+
+    pub fn render(&self, format: Format) -> String {
+        match format {
+            Format::Text => self.to_text(),
+            Format::Json => self.to_json(),
+            Format::Junit => self.to_junit(),
+        }
+    }
+
+    pub fn to_text(&self) -> String {
+        let mut out = String::new();
+        for publisher in &self.publishers {
+            writeln!(
+                out,
+                "publisher {}: sent {} in {:.3}s",
+                publisher.name,
+                publisher.sent,
+                publisher.elapsed.as_secs_f64()
+            )
+            .unwrap();
+            if publisher.reconnects > 0 {
+                writeln!(out, "  reconnected {} times", publisher.reconnects).unwrap();
+            }
+            for error in &publisher.errors {
+                writeln!(out, "  ERROR {}", error).unwrap();
+            }
+        }
+        for subscriber in &self.subscribers {
+            writeln!(
+                out,
+                "subscriber {}: received {} in {:.3}s",
+                subscriber.name,
+                subscriber.received,
+                subscriber.elapsed.as_secs_f64()
+            )
+            .unwrap();
+            if subscriber.reconnects > 0 {
+                writeln!(out, "  reconnected {} times", subscriber.reconnects).unwrap();
+            }
+            if let Some(ref error) = subscriber.error {
+                writeln!(out, "  ERROR {}", error).unwrap();
+            }
+            for sink in &subscriber.sinks {
+                let verdict = match sink.verdict {
+                    Verdict::Pass => "PASS",
+                    Verdict::Fail => "FAIL",
+                };
+                write!(
+                    out,
+                    "  {} {} after {:.3}s",
+                    verdict,
+                    sink.name,
+                    sink.elapsed.as_secs_f64()
+                )
+                .unwrap();
+                match sink.reason {
+                    Some(ref reason) => writeln!(out, ": {}", reason).unwrap(),
+                    None => writeln!(out).unwrap(),
+                }
+                for line in sink.summary.iter().flat_map(|summary| summary.lines()) {
+                    writeln!(out, "    {}", line).unwrap();
+                }
+            }
+        }
+        write!(
+            out,
+            "{}: {} failures, {} errors",
+            if self.success() { "PASS" } else { "FAIL" },
+            self.failures(),
+            self.errors()
+        )
+        .unwrap();
+        out
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).unwrap()
+    }
+
+    pub fn to_junit(&self) -> String {
+        let mut out = String::new();
+        writeln!(out, r#"<?xml version="1.0" encoding="UTF-8"?>"#).unwrap();
+        let tests = self.publishers.len()
+            + self
+                .subscribers
+                .iter()
+                .map(|subscriber| subscriber.sinks.len() + subscriber.error.iter().count())
+                .sum::<usize>();
+        writeln!(
+            out,
+            r#"<testsuites name="mqtt-verify" tests="{}" failures="{}" errors="{}">"#,
+            tests,
+            self.failures(),
+            self.errors()
+        )
+        .unwrap();
+        writeln!(
+            out,
+            r#"  <testsuite name="publishers" tests="{}" failures="0" errors="{}">"#,
+            self.publishers.len(),
+            self.publishers
+                .iter()
+                .filter(|publisher| !publisher.errors.is_empty())
+                .count()
+        )
+        .unwrap();
+        for publisher in &self.publishers {
+            writeln!(
+                out,
+                r#"    <testcase classname="publishers" name="{}" time="{:.3}">"#,
+                escape_xml(&publisher.name),
+                publisher.elapsed.as_secs_f64()
+            )
+            .unwrap();
+            if !publisher.errors.is_empty() {
+                writeln!(
+                    out,
+                    r#"      <error message="{}"/>"#,
+                    escape_xml(&publisher.errors.join("; "))
+                )
+                .unwrap();
+            }
+            writeln!(
+                out,
+                r#"      <system-out>sent {}, reconnected {} times</system-out>"#,
+                publisher.sent, publisher.reconnects
+            )
+            .unwrap();
+            writeln!(out, "    </testcase>").unwrap();
+        }
+        writeln!(out, "  </testsuite>").unwrap();
+        for subscriber in &self.subscribers {
+            let name = escape_xml(&subscriber.name);
+            writeln!(
+                out,
+                r#"  <testsuite name="{}" tests="{}" failures="{}" errors="{}">"#,
+                name,
+                subscriber.sinks.len() + subscriber.error.iter().count(),
+                subscriber
+                    .sinks
+                    .iter()
+                    .filter(|sink| sink.verdict == Verdict::Fail)
+                    .count(),
+                subscriber.error.iter().count()
+            )
+            .unwrap();
+            if let Some(ref error) = subscriber.error {
+                writeln!(
+                    out,
+                    r#"    <testcase classname="{}" name="connection" time="{:.3}">"#,
+                    name,
+                    subscriber.elapsed.as_secs_f64()
+                )
+                .unwrap();
+                writeln!(out, r#"      <error message="{}"/>"#, escape_xml(error)).unwrap();
+                writeln!(out, "    </testcase>").unwrap();
+            }
+            for sink in &subscriber.sinks {
+                writeln!(
+                    out,
+                    r#"    <testcase classname="{}" name="{}" time="{:.3}">"#,
+                    name,
+                    escape_xml(&sink.name),
+                    sink.elapsed.as_secs_f64()
+                )
+                .unwrap();
+                if let Some(ref reason) = sink.reason {
+                    writeln!(out, r#"      <failure message="{}"/>"#, escape_xml(reason)).unwrap();
+                }
+                if let Some(ref summary) = sink.summary {
+                    writeln!(
+                        out,
+                        "      <system-out>{}</system-out>",
+                        escape_xml(summary)
+                    )
+                    .unwrap();
+                }
+                writeln!(out, "    </testcase>").unwrap();
+            }
+            writeln!(out, "  </testsuite>").unwrap();
+        }
+        write!(out, "</testsuites>").unwrap();
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Format, PublisherReport, Report, SinkReport, SubscriberReport, Verdict};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    fn report() -> Report {
+        Report {
+            publishers: vec![PublisherReport {
+                name: "p-1".to_owned(),
+                sent: 10,
+                errors: vec![],
+                reconnects: 2,
+                elapsed: Duration::from_millis(1500),
+            }],
+            subscribers: vec![SubscriberReport {
+                name: "s-1".to_owned(),
+                received: 9,
+                error: None,
+                reconnects: 0,
+                sinks: vec![
+                    SinkReport {
+                        name: "latency".to_owned(),
+                        verdict: Verdict::Pass,
+                        reason: None,
+                        summary: Some("p50=1ms".to_owned()),
+                        elapsed: Duration::from_secs(2),
+                    },
+                    SinkReport {
+                        name: "sequence".to_owned(),
+                        verdict: Verdict::Fail,
+                        reason: Some("missing <3>".to_owned()),
+                        summary: None,
+                        elapsed: Duration::from_secs(2),
+                    },
+                ],
+                elapsed: Duration::from_secs(2),
+            }],
+        }
+    }
+
+    #[test]
+    fn counts_failures_and_errors() {
+        let mut report = report();
+        assert_eq!(1, report.failures());
+        assert_eq!(0, report.errors());
+        assert!(!report.success());
+        report.publishers[0].errors.push("borked".to_owned());
+        assert_eq!(1, report.errors());
+    }
+
+    #[test]
+    fn text_report() {
+        let text = report().to_text();
+        assert!(text.contains("publisher p-1: sent 10 in 1.500s\n  reconnected 2 times"));
+        assert!(!text.contains("subscriber s-1: received 9 in 2.000s\n  reconnected"));
+        assert!(text.contains("  FAIL sequence after 2.000s: missing <3>"));
+        assert!(text.contains("    p50=1ms"));
+        assert!(text.ends_with("FAIL: 1 failures, 0 errors"));
+    }
+
+    #[test]
+    fn json_report() {
+        let json: serde_json::Value = serde_json::from_str(&report().to_json()).unwrap();
+        assert_eq!(1.5, json["publishers"][0]["elapsed"]);
+        assert_eq!(2, json["publishers"][0]["reconnects"]);
+        assert_eq!("fail", json["subscribers"][0]["sinks"][1]["verdict"]);
+    }
+
+    #[test]
+    fn junit_report() {
+        let xml = report().to_junit();
+        assert!(
+            xml.contains(r#"<testsuites name="mqtt-verify" tests="3" failures="1" errors="0">"#)
+        );
+        assert!(xml.contains(r#"<failure message="missing &lt;3&gt;"/>"#));
+    }
+
+    #[test]
+    fn format_from_str() {
+        assert_eq!(Format::Junit, Format::from_str("junit").unwrap());
+        assert!(Format::from_str("yaml").is_err());
+    }
+}
@@ -1,22 +1,133 @@
 use crate::analyzers;
+use crate::backoff;
+use crate::credentials;
+use crate::errors::MqttVerifyError;
 use crate::source;
+use crate::tls;
+use crate::will;
 use paho_mqtt as mqtt;
+use std::collections::HashMap;
 use std::time::Duration;
 
 pub struct Scenario {
     pub publishers: Vec<Publisher>,
     pub subscribers: Vec<Subscriber>,
+    pub groups: Vec<SubscriberGroup>,
+    /// Upper bound on how long the whole scenario may run
+    pub deadline: Option<Duration>,
+    /// Leave retained messages on the broker instead of clearing them
+    pub keep_retained: bool,
+}
+
+impl Scenario {
+    /// Brokers disconnect a client when another connects with the same client
+    /// id, so require fixed ids to be unique. Empty ids are assigned by the
+    /// broker.
+    pub fn validate(&self) -> Result<(), MqttVerifyError> {
+        let clients = self
+            .publishers
+            .iter()
+            .map(|publisher| (&publisher.name, &publisher.client))
+            .chain(
+                self.subscribers
+                    .iter()
+                    .chain(self.groups.iter().flat_map(|group| group.members.iter()))
+                    .map(|subscriber| (&subscriber.name, &subscriber.client)),
+            );
+        let mut seen: HashMap<&str, &String> = HashMap::new();
+        for (name, client) in clients {
+            let client_id = crate::client_id(client);
+            if client_id.is_empty() {
+                continue;
+            }
+            if let Some(first) = seen.insert(client_id, name) {
+                return Err(MqttVerifyError::DuplicateClientId {
+                    client_id: client_id.to_owned(),
+                    name: name.clone(),
+                    first: first.clone(),
+                });
+            }
+        }
+        Ok(())
+    }
 }
 
 pub struct Publisher {
+    pub name: String,
     pub client: mqtt::AsyncClient,
     pub initial_timeout: Duration,
+    pub tls: Option<tls::TlsOptions>,
+    pub credentials: Option<credentials::Credentials>,
+    /// Delays between attempts to connect within the initial timeout
+    pub backoff: backoff::Backoff,
+    /// Connect again when the connection is lost instead of giving up
+    pub reconnect: bool,
+    pub will: Option<will::Will>,
+    /// Connect through this relay and sever it when done instead of
+    /// disconnecting, so that the broker publishes the will
+    pub relay: Option<will::Relay>,
     pub sources: Vec<source::VerifiableSource>,
 }
 
+impl Publisher {
+    /// Topics that retained messages from this publisher are left on.
+    pub fn retained_topics(&self) -> Result<Vec<String>, MqttVerifyError> {
+        let mut topics = Vec::new();
+        for source in &self.sources {
+            topics.extend(source.retained_values()?.keys().cloned());
+        }
+        topics.sort();
+        topics.dedup();
+        Ok(topics)
+    }
+}
+
+pub struct Subscription {
+    pub topic: String,
+    pub qos: i32,
+}
+
+/// Subscribe in a persistent session and go offline for a while, expecting
+/// the broker to queue what is published meanwhile.
+pub struct PersistentSession {
+    /// How long after subscribing to disconnect
+    pub offline_after: Duration,
+    /// How long to stay disconnected
+    pub offline_for: Duration,
+}
+
 pub struct Subscriber {
+    pub name: String,
     pub client: mqtt::AsyncClient,
     pub initial_timeout: Duration,
-    pub topics: Vec<String>,
+    pub tls: Option<tls::TlsOptions>,
+    pub credentials: Option<credentials::Credentials>,
+    /// Delays between attempts to connect within the initial timeout
+    pub backoff: backoff::Backoff,
+    /// Connect and subscribe again when the connection is lost instead of
+    /// giving up
+    pub reconnect: bool,
+    pub topics: Vec<Subscription>,
+    pub sinks: Vec<Box<dyn analyzers::Analyzer>>,
+    /// Give up when no message has arrived for this long
+    pub idle_timeout: Option<Duration>,
+    /// Connect only once all publishers are done, e.g. to receive just what
+    /// the broker retained
+    pub after_publishing: bool,
+    /// Requires a client with a fixed client id
+    pub session: Option<PersistentSession>,
+}
+
+/// Subscribers sharing a subscription, verified together. Each member still
+/// gets its own sinks, but the group's idle timeout applies instead of the
+/// members' and sessions and waiting for the publishers are not supported.
+pub struct SubscriberGroup {
+    pub name: String,
+    pub members: Vec<Subscriber>,
+    /// Fed the messages of all members
     pub sinks: Vec<Box<dyn analyzers::Analyzer>>,
+    /// Least fraction of an even share each member must receive
+    pub min_share: f64,
+    /// Give up when no member has received a message for this long
+    pub idle_timeout: Option<Duration>,
 }
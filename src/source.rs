@@ -1,42 +1,224 @@
 use crate::context::ContextualValue;
+use crate::errors::MqttVerifyError;
+use crate::v5;
+use evalexpr::Value;
 use futures::{future, stream::StreamExt};
 use futures_ticker::Ticker;
 use paho_mqtt as mqtt;
 use std::cell::Cell;
-use std::time::Duration;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 pub trait Source {
     fn messages(self) -> crate::MessageStream;
 }
 
+/// Verification marker embedded in payloads, on the form `id:seq/total@sent`
+/// where sent is microseconds since the epoch.
+#[derive(Debug, PartialEq)]
+pub struct Marker {
+    pub id: String,
+    pub seq: usize,
+    pub total: usize,
+    pub sent: Option<SystemTime>,
+}
+
+impl Marker {
+    pub fn new(id: String, seq: usize, total: usize) -> Self {
+        Self {
+            id,
+            seq,
+            total,
+            sent: Some(SystemTime::now()),
+        }
+    }
+
+    pub fn parse(payload: &str) -> Option<Self> {
+        let marker = payload.split_whitespace().next()?;
+        let colon = marker.rfind(':')?;
+        let mut counts = marker[colon + 1..].splitn(2, '/');
+        let seq = counts.next()?.parse().ok()?;
+        let mut total_sent = counts.next()?.splitn(2, '@');
+        let total = total_sent.next()?.parse().ok()?;
+        let sent = match total_sent.next() {
+            Some(micros) => Some(UNIX_EPOCH + Duration::from_micros(micros.parse().ok()?)),
+            None => None,
+        };
+        Some(Self {
+            id: marker[..colon].to_owned(),
+            seq,
+            total,
+            sent,
+        })
+    }
+
+    /// The part of `payload` following its marker, or all of it when it has
+    /// none.
+    pub fn strip(payload: &str) -> &str {
+        match Self::parse(payload) {
+            Some(_) => {
+                let payload = payload.trim_start();
+                match payload.find(char::is_whitespace) {
+                    Some(end) => &payload[end + 1..],
+                    None => "",
+                }
+            }
+            None => payload,
+        }
+    }
+
+    /// Variables describing the message, for expanding its topic and payload:
+    /// `id`, `seq`, `total` and `timestamp` in seconds since the epoch.
+    pub fn variables(&self) -> Vec<(String, Value)> {
+        let mut variables = vec![
+            ("id".to_owned(), Value::String(self.id.clone())),
+            ("seq".to_owned(), Value::Int(self.seq as i64)),
+            ("total".to_owned(), Value::Int(self.total as i64)),
+        ];
+        if let Some(sent) = self.sent {
+            let since_epoch = sent.duration_since(UNIX_EPOCH).unwrap_or_default();
+            variables.push((
+                "timestamp".to_owned(),
+                Value::Float(since_epoch.as_secs_f64()),
+            ));
+        }
+        variables
+    }
+}
+
+impl fmt::Display for Marker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}/{}", self.id, self.seq, self.total)?;
+        if let Some(sent) = self.sent {
+            let micros = sent.duration_since(UNIX_EPOCH).unwrap_or_default();
+            write!(f, "@{}", micros.as_micros())?;
+        }
+        Ok(())
+    }
+}
+
 pub struct VerifiableSource {
     id: String,
-    pub topic: ContextualValue,
+    /// Expanded for every message with the variables of its marker
+    pub topic: Rc<ContextualValue>,
+    payload: Option<ContextualValue>,
     seq_no: Cell<usize>,
     total_count: usize,
     frequency: f32,
+    qos: i32,
+    retained: bool,
+    properties: Option<v5::MessageProperties>,
 }
 
 impl VerifiableSource {
     pub fn new(id: String, topic: ContextualValue, total_count: usize, frequency: f32) -> Self {
         Self {
             id,
-            topic,
+            topic: Rc::new(topic),
+            payload: None,
             seq_no: Cell::new(0),
             total_count,
             frequency,
+            qos: 0,
+            retained: false,
+            properties: None,
+        }
+    }
+
+    /// Session id in the marker of every message.
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn with_qos(mut self, qos: i32) -> Self {
+        self.qos = qos;
+        self
+    }
+
+    /// Publish messages with the retained flag set.
+    pub fn with_retained(mut self, retained: bool) -> Self {
+        self.retained = retained;
+        self
+    }
+
+    /// Attach MQTT v5 properties to every message, ignored by brokers on
+    /// earlier versions.
+    pub fn with_properties(mut self, properties: v5::MessageProperties) -> Self {
+        self.properties = Some(properties);
+        self
+    }
+
+    /// Follow the verification marker in every payload with `payload`,
+    /// evaluated with the message's variables like the topic.
+    pub fn with_payload(mut self, payload: ContextualValue) -> Self {
+        self.payload = Some(payload);
+        self
+    }
+
+    /// Check the topic and payload templates against the variables of the
+    /// first message.
+    pub fn validate(&self) -> Result<(), MqttVerifyError> {
+        let variables = Marker::new(self.id.clone(), 1, self.total_count).variables();
+        self.topic.validate(variables.clone())?;
+        if let Some(ref payload) = self.payload {
+            payload.validate(variables.clone())?;
+        }
+        if let Some(ref properties) = self.properties {
+            properties.validate(&variables)?;
+        }
+        Ok(())
+    }
+
+    /// The marker of the last message published to each topic, which is the
+    /// one brokers keep when messages are retained. Empty unless they are.
+    pub fn retained_values(&self) -> Result<BTreeMap<String, Marker>, MqttVerifyError> {
+        let mut values = BTreeMap::new();
+        if self.retained {
+            for seq in 1..=self.total_count {
+                let marker = Marker::new(self.id.clone(), seq, self.total_count);
+                let topic = self.topic.value_with(marker.variables())?;
+                values.insert(
+                    topic,
+                    Marker {
+                        sent: None,
+                        ..marker
+                    },
+                );
+            }
         }
+        Ok(values)
     }
 
-    pub fn next_message(&self) -> Option<mqtt::Message> {
+    pub fn next_message(&self) -> Option<Result<mqtt::Message, MqttVerifyError>> {
         if self.seq_no.get() >= self.total_count {
             None
         } else {
             self.seq_no.set(self.seq_no.get() + 1);
-            let message = format!("{}:{}/{}", self.id, self.seq_no.get(), self.total_count);
-            Some(mqtt::Message::new(self.topic.value(), message, 0))
+            let marker = Marker::new(self.id.clone(), self.seq_no.get(), self.total_count);
+            Some(self.message(&marker))
         }
     }
+
+    fn message(&self, marker: &Marker) -> Result<mqtt::Message, MqttVerifyError> {
+        let topic = self.topic.value_with(marker.variables())?;
+        let payload = match self.payload {
+            Some(ref payload) => format!("{} {}", marker, payload.value_with(marker.variables())?),
+            None => marker.to_string(),
+        };
+        Ok(match self.properties {
+            Some(ref properties) => mqtt::MessageBuilder::new()
+                .topic(topic)
+                .payload(payload)
+                .qos(self.qos)
+                .retained(self.retained)
+                .properties(properties.expand(&marker.variables())?)
+                .finalize(),
+            None if self.retained => mqtt::Message::new_retained(topic, payload, self.qos),
+            None => mqtt::Message::new(topic, payload, self.qos),
+        })
+    }
 }
 
 impl Source for VerifiableSource {
@@ -47,35 +229,156 @@ impl Source for VerifiableSource {
             ))
             .map(move |_| self.next_message())
             .take_while(|message| future::ready(message.is_some()))
-            .map(|message| Ok(message.unwrap())),
+            .map(|message| message.unwrap()),
         )
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::context::{ContextualValue, OverlayContext};
-    use evalexpr::build_operator_tree;
+    use crate::context::OverlayContext;
+    use paho_mqtt as mqtt;
+    use std::time::{Duration, UNIX_EPOCH};
 
     #[test]
     fn verifiable_source_topic() {
-        let topic = ContextualValue::new(
-            build_operator_tree("\"ze-topic\"").unwrap(),
-            OverlayContext::root(),
-        );
+        let topic = OverlayContext::value_for(OverlayContext::root(), "ze-topic").unwrap();
         let source = super::VerifiableSource::new("id".to_owned(), topic, 1, 1.0);
-        assert_eq!("ze-topic", source.next_message().unwrap().topic());
+        assert_eq!("ze-topic", source.next_message().unwrap().unwrap().topic());
+    }
+
+    #[test]
+    fn verifiable_source_qos() {
+        let topic = OverlayContext::value_for(OverlayContext::root(), "ze-topic").unwrap();
+        let source = super::VerifiableSource::new("id".to_owned(), topic, 1, 1.0).with_qos(2);
+        assert_eq!(2, source.next_message().unwrap().unwrap().qos());
     }
 
     #[test]
     fn verifiable_source_iteration() {
-        let topic = ContextualValue::new(
-            build_operator_tree("\"ze-topic\"").unwrap(),
-            OverlayContext::root(),
-        );
+        let topic = OverlayContext::value_for(OverlayContext::root(), "ze-topic").unwrap();
         let source = super::VerifiableSource::new("id".to_owned(), topic, 2, 1.0);
-        assert_eq!("id:1/2", source.next_message().unwrap().payload_str());
-        assert_eq!("id:2/2", source.next_message().unwrap().payload_str());
+        assert!(source
+            .next_message()
+            .unwrap()
+            .unwrap()
+            .payload_str()
+            .starts_with("id:1/2@"));
+        assert!(source
+            .next_message()
+            .unwrap()
+            .unwrap()
+            .payload_str()
+            .starts_with("id:2/2@"));
         assert!(source.next_message().is_none());
     }
+
+    #[test]
+    fn verifiable_source_per_message_topic() {
+        let topic = OverlayContext::value_for(OverlayContext::root(), "sensors/{{seq % 2}}/{{id}}")
+            .unwrap();
+        let source = super::VerifiableSource::new("a".to_owned(), topic, 3, 1.0);
+        let topics: Vec<String> = (0..3)
+            .map(|_| source.next_message().unwrap().unwrap().topic().to_owned())
+            .collect();
+        assert_eq!(vec!["sensors/1/a", "sensors/0/a", "sensors/1/a"], topics);
+    }
+
+    #[test]
+    fn verifiable_source_payload() {
+        let topic = OverlayContext::value_for(OverlayContext::root(), "ze-topic").unwrap();
+        let payload = OverlayContext::value_for(
+            OverlayContext::root(),
+            r#"{"id": "{{id}}", "seq": {{seq}}, "left": {{total - seq}}}"#,
+        )
+        .unwrap();
+        let source =
+            super::VerifiableSource::new("id".to_owned(), topic, 3, 1.0).with_payload(payload);
+        let message = source.next_message().unwrap().unwrap();
+        let payload = message.payload_str();
+        let marker = super::Marker::parse(&payload).unwrap();
+        assert_eq!((1, 3), (marker.seq, marker.total));
+        assert!(payload.ends_with(r#" {"id": "id", "seq": 1, "left": 2}"#));
+    }
+
+    #[test]
+    fn verifiable_source_properties() {
+        let template = |value| OverlayContext::value_for(OverlayContext::root(), value).unwrap();
+        let properties = crate::v5::MessageProperties {
+            response_topic: Some(template("replies/{{id}}")),
+            correlation_data: Some(template("{{seq}}")),
+            ..Default::default()
+        };
+        let source = super::VerifiableSource::new("id".to_owned(), template("ze-topic"), 3, 1.0)
+            .with_retained(true)
+            .with_properties(properties);
+        source.validate().unwrap();
+        source.next_message();
+        let message = source.next_message().unwrap().unwrap();
+        assert!(message.retained());
+        assert_eq!(
+            Some("replies/id".to_owned()),
+            message
+                .properties()
+                .get_string(mqtt::PropertyCode::ResponseTopic)
+        );
+        assert_eq!(
+            Some(b"2".to_vec()),
+            message
+                .properties()
+                .get_binary(mqtt::PropertyCode::CorrelationData)
+        );
+    }
+
+    #[test]
+    fn verifiable_source_retained() {
+        let topic =
+            OverlayContext::value_for(OverlayContext::root(), "status/{{seq % 2}}").unwrap();
+        let source = super::VerifiableSource::new("id".to_owned(), topic, 5, 1.0);
+        assert!(source.retained_values().unwrap().is_empty());
+        let source = source.with_retained(true);
+        assert!(source.next_message().unwrap().unwrap().retained());
+        let values: Vec<(String, String)> = source
+            .retained_values()
+            .unwrap()
+            .into_iter()
+            .map(|(topic, marker)| (topic, marker.to_string()))
+            .collect();
+        assert_eq!(
+            vec![
+                ("status/0".to_owned(), "id:4/5".to_owned()),
+                ("status/1".to_owned(), "id:5/5".to_owned())
+            ],
+            values
+        );
+    }
+
+    #[test]
+    fn verifiable_source_validates_templates() {
+        let topic = OverlayContext::value_for(OverlayContext::root(), "{{seq}}/{{ip}}").unwrap();
+        let source = super::VerifiableSource::new("id".to_owned(), topic, 3, 1.0);
+        assert!(source.validate().is_err());
+        assert!(source.next_message().unwrap().is_err());
+    }
+
+    #[test]
+    fn marker_parse() {
+        let marker = super::Marker::parse("some:id:3/10").unwrap();
+        assert_eq!("some:id", marker.id);
+        assert_eq!(3, marker.seq);
+        assert_eq!(10, marker.total);
+        assert_eq!(None, marker.sent);
+        assert_eq!("some:id:3/10", marker.to_string());
+        let marker = super::Marker::parse("id:3/10@1500000 trailer").unwrap();
+        assert_eq!(
+            Some(UNIX_EPOCH + Duration::from_micros(1_500_000)),
+            marker.sent
+        );
+        assert_eq!("id:3/10@1500000", marker.to_string());
+        assert!(super::Marker::parse("id:3").is_none());
+        assert_eq!("trailer", super::Marker::strip("id:3/10@1500000 trailer"));
+        assert_eq!("", super::Marker::strip("id:3/10"));
+        assert_eq!("garbage in", super::Marker::strip("garbage in"));
+        assert!(super::Marker::parse("garbage").is_none());
+    }
 }
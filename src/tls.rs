@@ -0,0 +1,157 @@
+use crate::errors::MqttVerifyError;
+use async_std::{io, net::TcpStream};
+use paho_mqtt as mqtt;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// TLS settings for one broker connection. The server name sent for SNI and
+/// checked against the broker certificate is always the host part of the
+/// broker URI, as Paho cannot override it; connect by that name to brokers
+/// whose certificate names a different host.
+#[derive(Clone, Debug, Default)]
+pub struct TlsOptions {
+    /// PEM bundle of CAs trusted to sign the broker certificate
+    pub ca_file: Option<PathBuf>,
+    /// PEM client certificate presented for mutual TLS
+    pub cert_file: Option<PathBuf>,
+    /// PEM private key for the client certificate, unless included in it
+    pub key_file: Option<PathBuf>,
+    /// Accept any broker certificate and host name
+    pub insecure: bool,
+    /// Protocols offered with ALPN, e.g. "mqtt"
+    pub alpn: Vec<String>,
+}
+
+impl TlsOptions {
+    /// Whether the URI names a listener that requires TLS.
+    pub fn required_by(uri: &str) -> bool {
+        let scheme = uri.find("://").map_or("tcp", |i| &uri[..i]);
+        matches!(scheme, "ssl" | "mqtts" | "wss")
+    }
+
+    pub fn is_default(&self) -> bool {
+        self.ca_file.is_none()
+            && self.cert_file.is_none()
+            && self.key_file.is_none()
+            && !self.insecure
+            && self.alpn.is_empty()
+    }
+
+    pub fn ssl_options(&self) -> Result<mqtt::SslOptions, MqttVerifyError> {
+        let mut builder = mqtt::SslOptionsBuilder::new();
+        if let Some(ref path) = self.ca_file {
+            builder
+                .trust_store(readable(path)?)
+                .map_err(option_error(path))?;
+        }
+        if let Some(ref path) = self.cert_file {
+            builder
+                .key_store(readable(path)?)
+                .map_err(option_error(path))?;
+        }
+        if let Some(ref path) = self.key_file {
+            builder
+                .private_key(readable(path)?)
+                .map_err(option_error(path))?;
+        }
+        builder
+            .enable_server_cert_auth(!self.insecure)
+            .verify(!self.insecure);
+        if !self.alpn.is_empty() {
+            let protos: Vec<&str> = self.alpn.iter().map(String::as_str).collect();
+            builder.alpn_protos(&protos);
+        }
+        Ok(builder.finalize())
+    }
+}
+
+/// Paho only reads the files once connecting, where a missing file is
+/// indistinguishable from a failed handshake.
+fn readable(path: &Path) -> Result<&Path, MqttVerifyError> {
+    File::open(path)
+        .map(|_| path)
+        .map_err(|err| MqttVerifyError::TlsFileError {
+            path: path.display().to_string(),
+            source: err,
+        })
+}
+
+fn option_error(path: &Path) -> impl FnOnce(mqtt::Error) -> MqttVerifyError + '_ {
+    move |err| MqttVerifyError::TlsOptionError {
+        path: path.display().to_string(),
+        source: err,
+    }
+}
+
+/// The host:port a broker URI connects to.
+pub(crate) fn broker_address(uri: &str) -> String {
+    let (scheme, rest) = match uri.find("://") {
+        Some(i) => (&uri[..i], &uri[i + 3..]),
+        None => ("tcp", uri),
+    };
+    let authority = rest.split('/').next().unwrap_or(rest);
+    let has_port = match authority.rfind(':') {
+        Some(i) => !authority[i..].contains(']'),
+        None => false,
+    };
+    if has_port {
+        return authority.to_owned();
+    }
+    let port = match scheme {
+        "ssl" | "mqtts" => 8883,
+        "wss" => 443,
+        "ws" => 80,
+        _ => 1883,
+    };
+    format!("{}:{}", authority, port)
+}
+
+/// Whether a failed TLS connect got as far as the handshake. Paho reports
+/// unreachable brokers and rejected handshakes alike, so check whether the
+/// listener accepts plain TCP connections.
+pub async fn failed_in_handshake(uri: &str, err: &mqtt::Error, timeout: Duration) -> bool {
+    match err {
+        // A positive code is a CONNACK refusal, so the handshake succeeded
+        mqtt::Error::Paho(rc) | mqtt::Error::PahoDescr(rc, _) if *rc > 0 => false,
+        _ => io::timeout(timeout, TcpStream::connect(broker_address(uri)))
+            .await
+            .is_ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{broker_address, TlsOptions};
+    use crate::errors::MqttVerifyError;
+    use std::path::PathBuf;
+
+    #[test]
+    fn broker_address_defaults_port_by_scheme() {
+        assert_eq!("broker:8883", broker_address("ssl://broker"));
+        assert_eq!("broker:1883", broker_address("tcp://broker"));
+        assert_eq!("broker:9000", broker_address("mqtts://broker:9000"));
+        assert_eq!("[::1]:443", broker_address("wss://[::1]/mqtt"));
+    }
+
+    #[test]
+    fn required_by_tls_schemes() {
+        assert!(TlsOptions::required_by("ssl://broker:8883"));
+        assert!(!TlsOptions::required_by("tcp://broker:1883"));
+        assert!(!TlsOptions::required_by("broker"));
+    }
+
+    #[test]
+    fn missing_ca_file_is_reported_up_front() {
+        let tls = TlsOptions {
+            ca_file: Some(PathBuf::from("/nonexistent/ca.pem")),
+            ..TlsOptions::default()
+        };
+        match tls.ssl_options() {
+            Err(MqttVerifyError::TlsFileError { path, .. }) => {
+                assert_eq!("/nonexistent/ca.pem", path)
+            }
+            _ => panic!("Expected missing file"),
+        }
+    }
+}
@@ -0,0 +1,231 @@
+use crate::context::ContextualValue;
+use crate::errors::MqttVerifyError;
+use crate::parameters;
+use evalexpr::Value;
+use paho_mqtt as mqtt;
+
+/// Parse an MQTT protocol version: 3.1, 3.1.1 or 5.
+pub fn parse_version(input: &str) -> Result<u32, MqttVerifyError> {
+    match input {
+        "3.1" => Ok(mqtt::MQTT_VERSION_3_1),
+        "3.1.1" => Ok(mqtt::MQTT_VERSION_3_1_1),
+        "5" | "5.0" => Ok(mqtt::MQTT_VERSION_5),
+        _ => Err(MqttVerifyError::MalformedValue {
+            value: input.to_owned(),
+        }),
+    }
+}
+
+/// Parse a user property as `key=value`.
+pub fn parse_user_property(input: &str) -> Result<(String, String), MqttVerifyError> {
+    let (key, value) = parameters::split_on_equal(input)?;
+    Ok((key.to_owned(), value.to_owned()))
+}
+
+/// The reason code of an MQTT v5 refusal behind a Paho error, if any.
+pub fn reason_code(err: &mqtt::Error) -> Option<i32> {
+    match err {
+        mqtt::Error::ReasonCode(reason_code) => Some(*reason_code as i32),
+        _ => None,
+    }
+}
+
+/// How long brokers keep a persistent session after the client disconnects.
+/// MQTT v5 sessions end with the connection unless told otherwise.
+const SESSION_EXPIRY_SECS: i32 = 3600;
+
+/// Connect properties asking the broker to keep the session.
+pub fn session_properties() -> Result<mqtt::Properties, MqttVerifyError> {
+    let mut properties = mqtt::Properties::new();
+    properties
+        .push_int(
+            mqtt::PropertyCode::SessionExpiryInterval,
+            SESSION_EXPIRY_SECS,
+        )
+        .map_err(|err| MqttVerifyError::PropertyError { source: err })?;
+    Ok(properties)
+}
+
+/// Topic filter for a shared subscription to `topic`, delivering each message
+/// to only one subscriber in `group`.
+pub fn shared(group: &str, topic: &str) -> String {
+    format!("$share/{}/{}", group, topic)
+}
+
+/// MQTT v5 properties of published messages. Templates are expanded with the
+/// variables of each message like its topic.
+#[derive(Default)]
+pub struct MessageProperties {
+    pub user: Vec<(String, ContextualValue)>,
+    /// Seconds until the broker may discard the message
+    pub message_expiry: Option<u32>,
+    pub content_type: Option<String>,
+    pub response_topic: Option<ContextualValue>,
+    pub correlation_data: Option<ContextualValue>,
+}
+
+impl MessageProperties {
+    pub fn validate(&self, variables: &[(String, Value)]) -> Result<(), MqttVerifyError> {
+        let templates = self
+            .user
+            .iter()
+            .map(|(_, value)| value)
+            .chain(self.response_topic.iter())
+            .chain(self.correlation_data.iter());
+        for template in templates {
+            template.validate(variables.to_vec())?;
+        }
+        Ok(())
+    }
+
+    pub fn expand(
+        &self,
+        variables: &[(String, Value)],
+    ) -> Result<mqtt::Properties, MqttVerifyError> {
+        let property_error = |err| MqttVerifyError::PropertyError { source: err };
+        let mut properties = mqtt::Properties::new();
+        for (key, value) in &self.user {
+            let value = value.value_with(variables.to_vec())?;
+            properties
+                .push_string_pair(mqtt::PropertyCode::UserProperty, key, &value)
+                .map_err(property_error)?;
+        }
+        if let Some(expiry) = self.message_expiry {
+            properties
+                .push_int(mqtt::PropertyCode::MessageExpiryInterval, expiry as i32)
+                .map_err(property_error)?;
+        }
+        if let Some(ref content_type) = self.content_type {
+            properties
+                .push_string(mqtt::PropertyCode::ContentType, content_type)
+                .map_err(property_error)?;
+        }
+        if let Some(ref topic) = self.response_topic {
+            let topic = topic.value_with(variables.to_vec())?;
+            properties
+                .push_string(mqtt::PropertyCode::ResponseTopic, &topic)
+                .map_err(property_error)?;
+        }
+        if let Some(ref data) = self.correlation_data {
+            let data = data.value_with(variables.to_vec())?;
+            properties
+                .push_binary(mqtt::PropertyCode::CorrelationData, data)
+                .map_err(property_error)?;
+        }
+        Ok(properties)
+    }
+}
+
+/// Variables for the v5 properties of a received message: `content_type`,
+/// `response_topic`, `correlation_data` and `message_expiry` when present,
+/// and `user_<key>` for each user property, with characters other than
+/// letters, digits and underscores in the key replaced by underscores.
+pub fn variables(message: &mqtt::Message) -> Vec<(String, Value)> {
+    let properties = message.properties();
+    let mut variables = Vec::new();
+    let strings = vec![
+        ("content_type", mqtt::PropertyCode::ContentType),
+        ("response_topic", mqtt::PropertyCode::ResponseTopic),
+    ];
+    for (name, code) in strings {
+        if let Some(value) = properties.get_string(code) {
+            variables.push((name.to_owned(), Value::String(value)));
+        }
+    }
+    if let Some(data) = properties.get_binary(mqtt::PropertyCode::CorrelationData) {
+        let data = String::from_utf8_lossy(&data).into_owned();
+        variables.push(("correlation_data".to_owned(), Value::String(data)));
+    }
+    if let Some(expiry) = properties.get_int(mqtt::PropertyCode::MessageExpiryInterval) {
+        variables.push(("message_expiry".to_owned(), Value::Int(expiry as i64)));
+    }
+    let mut index = 0;
+    while let Some((key, value)) =
+        properties.get_string_pair_at(mqtt::PropertyCode::UserProperty, index)
+    {
+        let key: String = key
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect();
+        variables.push((format!("user_{}", key), Value::String(value)));
+        index += 1;
+    }
+    variables
+}
+
+/// A sample value for `name` if it is the variable of a message property,
+/// which only messages with that property have.
+pub fn sample_variable(name: &str) -> Option<Value> {
+    match name {
+        "content_type" | "response_topic" | "correlation_data" => {
+            Some(Value::String(String::new()))
+        }
+        "message_expiry" => Some(Value::Int(0)),
+        _ if name.starts_with("user_") => Some(Value::String(String::new())),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MessageProperties;
+    use crate::context::OverlayContext;
+    use crate::errors::MqttVerifyError;
+    use evalexpr::Value;
+    use paho_mqtt as mqtt;
+
+    #[test]
+    fn parse_version() {
+        assert_eq!(mqtt::MQTT_VERSION_5, super::parse_version("5").unwrap());
+        assert_eq!(
+            mqtt::MQTT_VERSION_3_1_1,
+            super::parse_version("3.1.1").unwrap()
+        );
+        assert!(super::parse_version("4").is_err());
+    }
+
+    #[test]
+    fn parse_user_property() {
+        assert_eq!(
+            ("origin".to_owned(), "a=b".to_owned()),
+            super::parse_user_property("origin=a=b").unwrap()
+        );
+        assert!(super::parse_user_property("origin").is_err());
+    }
+
+    #[test]
+    fn expanded_properties_become_variables() -> Result<(), MqttVerifyError> {
+        let template = |value| OverlayContext::value_for(OverlayContext::root(), value);
+        let properties = MessageProperties {
+            user: vec![("trace-id".to_owned(), template("t-{{seq}}")?)],
+            message_expiry: Some(60),
+            content_type: Some("text/plain".to_owned()),
+            response_topic: Some(template("replies/{{seq}}")?),
+            correlation_data: Some(template("{{seq}}")?),
+        };
+        let variables = vec![("seq".to_owned(), Value::Int(7))];
+        properties.validate(&variables)?;
+        let message = mqtt::MessageBuilder::new()
+            .topic("ze-topic")
+            .payload("payload")
+            .properties(properties.expand(&variables)?)
+            .finalize();
+        assert_eq!(
+            vec![
+                (
+                    "content_type".to_owned(),
+                    Value::String("text/plain".to_owned())
+                ),
+                (
+                    "response_topic".to_owned(),
+                    Value::String("replies/7".to_owned())
+                ),
+                ("correlation_data".to_owned(), Value::String("7".to_owned())),
+                ("message_expiry".to_owned(), Value::Int(60)),
+                ("user_trace_id".to_owned(), Value::String("t-7".to_owned())),
+            ],
+            super::variables(&message)
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,194 @@
+use crate::errors::MqttVerifyError;
+use crate::tls;
+use async_std::net::{Shutdown, TcpListener, TcpStream};
+use async_std::{io, task};
+use futures::{future, stream::StreamExt};
+use log::warn;
+use paho_mqtt as mqtt;
+use std::net::{self, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Last Will and Testament the broker publishes when the client drops
+/// without disconnecting.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Will {
+    pub topic: String,
+    pub payload: String,
+    pub qos: i32,
+    pub retained: bool,
+}
+
+impl Will {
+    pub fn message(&self) -> mqtt::Message {
+        if self.retained {
+            mqtt::Message::new_retained(self.topic.clone(), self.payload.clone(), self.qos)
+        } else {
+            mqtt::Message::new(self.topic.clone(), self.payload.clone(), self.qos)
+        }
+    }
+}
+
+/// The broker URI with its address replaced by `local`.
+fn relayed_uri(uri: &str, local: SocketAddr) -> String {
+    let (scheme, rest) = match uri.find("://") {
+        Some(i) => (&uri[..i], &uri[i + 3..]),
+        None => ("tcp", uri),
+    };
+    let path = match rest.find('/') {
+        Some(i) => &rest[i..],
+        None => "",
+    };
+    format!("{}://{}{}", scheme, local, path)
+}
+
+/// Local TCP relay to a broker that can be severed, dropping the connection
+/// without an MQTT DISCONNECT like a crashed client would. Paho always sends
+/// one when disconnecting or destroying a client.
+pub struct Relay {
+    listener: Option<net::TcpListener>,
+    broker: String,
+    broker_uri: String,
+    uri: String,
+    severed: Arc<AtomicBool>,
+    streams: Arc<Mutex<Vec<TcpStream>>>,
+}
+
+impl Relay {
+    pub fn bind(broker_uri: &str) -> Result<Self, MqttVerifyError> {
+        let relay_error = |err| MqttVerifyError::RelayError { source: err };
+        let listener = net::TcpListener::bind("127.0.0.1:0").map_err(relay_error)?;
+        listener.set_nonblocking(true).map_err(relay_error)?;
+        let uri = relayed_uri(broker_uri, listener.local_addr().map_err(relay_error)?);
+        Ok(Self {
+            listener: Some(listener),
+            broker: tls::broker_address(broker_uri),
+            broker_uri: broker_uri.to_owned(),
+            uri,
+            severed: Arc::new(AtomicBool::new(false)),
+            streams: Arc::new(Mutex::new(Vec::new())),
+        })
+    }
+
+    /// URI for clients to connect to the broker through the relay.
+    pub fn uri(&self) -> &str {
+        &self.uri
+    }
+
+    /// URI of the broker itself, for clients that must outlive the relay.
+    pub fn broker_uri(&self) -> &str {
+        &self.broker_uri
+    }
+
+    /// Relay connections in the background until severed.
+    pub fn start(&mut self) {
+        let listener = match self.listener.take() {
+            Some(listener) => TcpListener::from(listener),
+            None => return,
+        };
+        let broker = self.broker.clone();
+        let severed = self.severed.clone();
+        let streams = self.streams.clone();
+        task::spawn(async move {
+            let mut incoming = listener.incoming();
+            while let Some(client) = incoming.next().await {
+                if severed.load(Ordering::SeqCst) {
+                    break;
+                }
+                let relayed = match client {
+                    Ok(client) => TcpStream::connect(&broker)
+                        .await
+                        .map(|broker| (client, broker)),
+                    Err(err) => Err(err),
+                };
+                let (client, broker) = match relayed {
+                    Ok(relayed) => relayed,
+                    Err(err) => {
+                        warn!("Relaying to {} borked: {}", broker, err);
+                        continue;
+                    }
+                };
+                streams
+                    .lock()
+                    .unwrap()
+                    .extend(vec![client.clone(), broker.clone()]);
+                task::spawn(async move {
+                    let (mut client_reader, mut client_writer) = (&client, &client);
+                    let (mut broker_reader, mut broker_writer) = (&broker, &broker);
+                    let upstream = io::copy(&mut client_reader, &mut broker_writer);
+                    let downstream = io::copy(&mut broker_reader, &mut client_writer);
+                    future::select(Box::pin(upstream), Box::pin(downstream)).await;
+                    let _ = client.shutdown(Shutdown::Both);
+                    let _ = broker.shutdown(Shutdown::Both);
+                });
+            }
+        });
+    }
+
+    /// Cut all relayed connections and refuse new ones.
+    pub fn sever(&self) {
+        self.severed.store(true, Ordering::SeqCst);
+        for stream in self.streams.lock().unwrap().drain(..) {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{relayed_uri, Relay};
+    use async_std::net::TcpStream;
+    use async_std::prelude::*;
+    use async_std::task;
+    use std::io::{Read, Write};
+    use std::net;
+    use std::thread;
+
+    #[test]
+    fn relay_until_severed() {
+        let broker = net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("tcp://{}", broker.local_addr().unwrap());
+        let echo = thread::spawn(move || {
+            let (mut stream, _) = broker.accept().unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).unwrap();
+            stream.write_all(&buffer).unwrap();
+            // Severing the relay closes the connection to the broker
+            stream.read(&mut buffer).unwrap()
+        });
+        let mut relay = Relay::bind(&uri).unwrap();
+        let address = relay.uri()["tcp://".len()..].to_owned();
+        task::block_on(async {
+            relay.start();
+            let mut stream = TcpStream::connect(address).await.unwrap();
+            stream.write_all(b"ping").await.unwrap();
+            let mut buffer = [0; 4];
+            stream.read_exact(&mut buffer).await.unwrap();
+            assert_eq!(b"ping", &buffer);
+            relay.sever();
+            assert_eq!(0, stream.read(&mut buffer).await.unwrap_or(0));
+        });
+        assert_eq!(0, echo.join().unwrap());
+    }
+
+    #[test]
+    fn relay_remembers_broker_uri() {
+        let relay = Relay::bind("tcp://localhost:1883").unwrap();
+        assert_eq!("tcp://localhost:1883", relay.broker_uri());
+        assert!(relay.uri().starts_with("tcp://127.0.0.1:"));
+    }
+
+    #[test]
+    fn relayed_uri_keeps_scheme_and_path() {
+        let local = "127.0.0.1:4000".parse().unwrap();
+        assert_eq!(
+            "tcp://127.0.0.1:4000",
+            relayed_uri("tcp://broker:1883", local)
+        );
+        assert_eq!(
+            "wss://127.0.0.1:4000/mqtt",
+            relayed_uri("wss://broker/mqtt", local)
+        );
+        assert_eq!("tcp://127.0.0.1:4000", relayed_uri("broker", local));
+    }
+}
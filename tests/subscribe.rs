@@ -9,7 +9,9 @@ use futures::{
 };
 use futures_timer::Delay;
 use mqtt_verify::analyzers;
+use mqtt_verify::backoff;
 use mqtt_verify::errors;
+use mqtt_verify::report;
 use mqtt_verify::scenario;
 use paho_mqtt as mqtt;
 use rand::{distributions::Alphanumeric, thread_rng, Rng};
@@ -106,6 +108,10 @@ impl analyzers::Analyzer for TestingSink {
         self.received.borrow_mut().push(message);
         Ok(analyzers::State::Done)
     }
+
+    fn describe(&self) -> String {
+        "testing".to_owned()
+    }
 }
 
 fn make_subscriber(
@@ -117,10 +123,21 @@ fn make_subscriber(
         received: received.clone(),
     };
     let subscriber = scenario::Subscriber {
+        name: "subscriber".to_owned(),
         client: client(port),
         initial_timeout: Duration::from_millis(1000),
-        topics: vec![topic_name],
+        tls: None,
+        credentials: None,
+        backoff: backoff::Backoff::default(),
+        reconnect: false,
+        topics: vec![scenario::Subscription {
+            topic: topic_name,
+            qos: 0,
+        }],
         sinks: vec![Box::new(sink)],
+        idle_timeout: None,
+        after_publishing: false,
+        session: None,
     };
     (subscriber, received)
 }
@@ -134,9 +151,11 @@ async fn terminate_when_analyzer_done() {
     let publish = Delay::new(Duration::from_millis(100))
         .then(|_| publish_message(port, mqtt::Message::new(topic_name, "payload", 0)));
     let (s_err, p_err) = join(subscriber, publish).await;
-    s_err.unwrap();
+    let subscriber_report = s_err.unwrap();
     p_err.unwrap();
     assert_eq!(1, received.borrow().len());
+    assert_eq!(1, subscriber_report.received);
+    assert_eq!(report::Verdict::Pass, subscriber_report.sinks[0].verdict);
 }
 
 #[tokio::test]